@@ -12,6 +12,17 @@ pub fn validate_arguments(args: Vec<String>) -> Result<Args, Error> {
     Args::try_parse_from(args).context("Failed attempt at parsing args")
 }
 
+/// Parse the arguments that follow the word `undo`. The clap error is kept so
+/// `--help` and usage errors print the way clap formats them.
+pub(crate) fn validate_undo_arguments(args: Vec<String>) -> Result<UndoArgs, clap::Error> {
+    UndoArgs::try_parse_from(args)
+}
+
+/// Parse the arguments that follow the word `recover`.
+pub(crate) fn validate_recover_arguments(args: Vec<String>) -> Result<RecoverArgs, clap::Error> {
+    RecoverArgs::try_parse_from(args)
+}
+
 pub fn print_usage() {
     Args::command()
         .print_help()
@@ -111,6 +122,84 @@ pub fn canonicalize_roots(roots: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
         .collect()
 }
 
+/// Where the CLI keeps run journals when `--state-dir` is not given:
+/// `$XDG_STATE_HOME/czkawka-dupes-to-symlinks`, `~/.local/state/...` on Unix,
+/// or `%LOCALAPPDATA%\czkawka-dupes-to-symlinks` on Windows.
+pub(crate) fn default_state_dir() -> Option<PathBuf> {
+    const APP_DIR: &str = "czkawka-dupes-to-symlinks";
+
+    let non_empty = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+
+    #[cfg(target_family = "windows")]
+    {
+        non_empty("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join(APP_DIR))
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    {
+        non_empty("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                non_empty("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+            })
+            .map(|dir| dir.join(APP_DIR))
+    }
+}
+
+/// Where the CLI keeps run locks when `--lock-dir` is not given. It has to be
+/// the same for every user, so it is not derived from the environment, and it
+/// lives under a system directory so nobody can plant it in `/tmp`:
+/// `/run/lock/czkawka-dupes-to-symlinks` on Linux,
+/// `/var/run/czkawka-dupes-to-symlinks` on other Unixes, and
+/// `%ProgramData%\czkawka-dupes-to-symlinks\locks` on Windows.
+///
+/// Where that directory does not exist and this user cannot create it (e.g.
+/// `/run/lock` is `0755 root`), `None` is returned after saying so, and runs
+/// lock in `<state-dir>/locks` instead, which only this user's runs see.
+pub(crate) fn default_lock_dir() -> Option<PathBuf> {
+    #[cfg(target_family = "windows")]
+    {
+        Some(
+            std::env::var_os("ProgramData")
+                .filter(|value| !value.is_empty())
+                .map_or_else(|| PathBuf::from(r"C:\ProgramData"), PathBuf::from)
+                .join("czkawka-dupes-to-symlinks")
+                .join("locks"),
+        )
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    {
+        #[cfg(target_os = "linux")]
+        let dir = PathBuf::from("/run/lock/czkawka-dupes-to-symlinks");
+        #[cfg(not(target_os = "linux"))]
+        let dir = PathBuf::from("/var/run/czkawka-dupes-to-symlinks");
+
+        if std::fs::symlink_metadata(&dir).is_ok() || dir.parent().is_some_and(is_writable) {
+            return Some(dir);
+        }
+
+        eprintln!(
+            "Note: cannot create the shared lock directory '{}'; locking in <state-dir>/locks, which runs as other users do not see. Pass --lock-dir to share one.",
+            dir.display()
+        );
+        None
+    }
+}
+
+/// Whether this user may create entries in `dir`.
+#[cfg(not(target_family = "windows"))]
+fn is_writable(dir: &std::path::Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+
+    // SAFETY: `dir` is a valid NUL-terminated string for the whole call.
+    unsafe { libc::access(dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
 pub(crate) fn get_all_files(input_file_path: &str) -> Result<Vec<PathBuf>, Error> {
     let path = std::path::Path::new(input_file_path);
 
@@ -196,8 +285,14 @@ fn czkawka_duplicate_file_json_schema() -> serde_json::Value {
       }
     })
 }
-#[derive(clap::Parser)]
-#[clap(author, version, about, long_about = None)]
+#[derive(clap::Parser, Default)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Other commands:\n  undo <RUN_ID>          Turn every link a finished run created back into a real copy (see `undo --help`)\n  recover --root <PATH>  Find backups left behind by interrupted runs (see `recover --help`)"
+)]
 /// Normalized CLI arguments that can also be constructed programmatically when
 /// embedding the crate.
 pub struct Args {
@@ -216,6 +311,109 @@ pub struct Args {
     #[arg(short, long, value_enum, default_value_t = OriginalToKeep::Newest)]
     pub original_to_keep: OriginalToKeep,
 
+    /// How each duplicate is replaced once it has been staged, or `dedupe` to
+    /// share extents in place without staging anything.
+    #[arg(short, long, value_enum, default_value_t = LinkType::Symlink)]
+    pub link_type: LinkType,
+
+    /// What new symlinks point at: the original's path as the report spells it,
+    /// its canonicalized path, or a path relative to the duplicate's directory.
+    #[arg(long, value_enum, default_value_t = SymlinkTarget::Reported)]
+    pub symlink_target: SymlinkTarget,
+
+    /// What to do with a report entry that is itself a symlink to a file
+    /// outside its group: leave it alone, fold its target into the group, or
+    /// leave the whole group alone with an error. The choice is printed at the
+    /// start of each run.
+    #[arg(long, value_enum, default_value_t = ExistingSymlinks::Skip)]
+    pub existing_symlinks: ExistingSymlinks,
+
+    /// Content-addressed vault directory (inside an allow-root). The original
+    /// of each group is moved to `<VAULT>/blake3/ab/cd/<hash>`, named by a
+    /// BLAKE3 of its content, and every member, including the former original
+    /// path, becomes a link to it. Later runs reuse an existing vault object
+    /// for the same content once its bytes match the group's original.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub vault: Option<PathBuf>,
+
+    /// Preferred home for real files (inside an allow-root). When no member of
+    /// a group already lives under it, the chosen original is moved there,
+    /// keeping its path relative to its allow-root, and every other member,
+    /// including the old location, becomes a link to the new spot.
+    #[arg(long, value_name = "PATH", conflicts_with = "vault", value_parser = clap::value_parser!(PathBuf))]
+    pub canonical_dir: Option<PathBuf>,
+
+    /// Number of real files to keep per group (default 1). Extra copies are
+    /// spread according to `--spread-by`; every other duplicate links to the
+    /// nearest kept copy.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["vault", "canonical_dir"])]
+    pub keep_copies: Option<u32>,
+
+    /// What the copies kept by `--keep-copies` should be spread across.
+    #[arg(long, value_enum, default_value_t = SpreadBy::Device)]
+    pub spread_by: SpreadBy,
+
+    /// Replace a whole duplicate directory tree with one directory symlink when
+    /// a live walk confirms it holds nothing but files the reports pair up
+    /// one-to-one with another tree.
+    #[arg(long, default_value_t = false)]
+    pub dedupe_directories: bool,
+
+    /// Where replaced files are parked until their links are in place. The run
+    /// summary names the location.
+    #[arg(long, value_enum, default_value_t = Staging::Suffix)]
+    pub staging: Staging,
+
+    /// Treat each duplicate group as one transaction: every backup is kept
+    /// until all members are linked, and any failure or skip restores every
+    /// member of the group.
+    #[arg(long, default_value_t = false)]
+    pub atomic_groups: bool,
+
+    /// Fsync new files and the directories touched by every stage, link and
+    /// backup removal, and delete a backup only once its link is on disk.
+    /// Slower, but what a run reports survives a power loss.
+    #[arg(long, default_value_t = false)]
+    pub durable: bool,
+
+    /// Directory holding the write-ahead journals of past runs. The CLI
+    /// defaults to `$XDG_STATE_HOME/czkawka-dupes-to-symlinks` (falling back to
+    /// `~/.local/state/czkawka-dupes-to-symlinks`) and refuses to run when
+    /// neither is set; library callers only get a journal when they set it.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub state_dir: Option<PathBuf>,
+
+    /// What to do with replacements left in flight by an interrupted run
+    /// under `allow_roots`. Without it, a run refuses to start while such an
+    /// entry exists.
+    #[arg(long, value_enum, value_name = "ACTION")]
+    pub resume_journal: Option<ResumeJournal>,
+
+    /// Apply `resume_journal` to this interrupted run only; repeat for more.
+    /// Runs left out still block the start, so each can get its own action.
+    /// Without it the action applies to every interrupted run.
+    #[arg(
+        long = "resume-run",
+        value_name = "RUN_ID",
+        requires = "resume_journal"
+    )]
+    pub resume_runs: Vec<String>,
+
+    /// Wait for a run holding an overlapping allow-root to finish instead of
+    /// refusing to start. Runs lock their roots in `lock_dir`.
+    #[arg(long, default_value_t = false)]
+    pub wait: bool,
+
+    /// Directory holding the run locks. Every user running over the same
+    /// trees must use the same one; the CLI defaults to
+    /// `/run/lock/czkawka-dupes-to-symlinks` (`/var/run` on other Unixes,
+    /// `%ProgramData%` on Windows), or `<state_dir>/locks` where this user
+    /// cannot create that. It must not be a symlink, and must be owned by root
+    /// or the current user unless it is world-writable and sticky like `/tmp`.
+    /// Library callers without it lock in `<state_dir>/locks`.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub lock_dir: Option<PathBuf>,
+
     /// Canonicalized directories that bound filesystem changes.
     ///
     /// Every duplicate must live under one of these roots or it will be skipped
@@ -224,7 +422,71 @@ pub struct Args {
     pub allow_roots: Vec<PathBuf>,
 }
 
-#[derive(ValueEnum, Clone)]
+#[derive(clap::Parser, Default)]
+#[clap(
+    name = "undo",
+    bin_name = "czkawka-dupes-to-symlinks undo",
+    version,
+    about = "Turn every link a finished run created back into a real copy"
+)]
+/// Arguments of the `undo` command, which reverses a finished run using its
+/// journal.
+pub struct UndoArgs {
+    /// Id of the run to reverse, as printed in its summary.
+    #[arg(value_name = "RUN_ID")]
+    pub run_id: String,
+
+    /// Report what would be restored without touching the filesystem.
+    #[arg(short, long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Directory holding the run journals; same default as the main command.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub state_dir: Option<PathBuf>,
+
+    /// Directory holding the run locks; same default as the main command.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub lock_dir: Option<PathBuf>,
+
+    /// Canonicalized directories that bound filesystem changes. Links outside
+    /// them are reported and left alone.
+    #[arg(long = "allow-root", value_name = "PATH", num_args = 1.., value_parser = clap::value_parser!(PathBuf))]
+    pub allow_roots: Vec<PathBuf>,
+}
+
+#[derive(clap::Parser, Default)]
+#[clap(
+    name = "recover",
+    bin_name = "czkawka-dupes-to-symlinks recover",
+    version,
+    about = "Find backups left behind by interrupted runs and restore, delete, or list them"
+)]
+/// Arguments of the `recover` command, which scans for orphaned backups.
+pub struct RecoverArgs {
+    /// Directories to scan. Each one also bounds what `recover` may change.
+    #[arg(long = "root", value_name = "PATH", required = true, num_args = 1.., value_parser = clap::value_parser!(PathBuf))]
+    pub roots: Vec<PathBuf>,
+
+    /// What to do with each backup found (default `leave`, which only lists
+    /// them).
+    #[arg(long, value_enum, default_value_t = RecoverAction::Leave)]
+    pub action: RecoverAction,
+
+    /// Report what the action would do without touching the filesystem.
+    #[arg(short, long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Directory holding the run journals, used to tell which file a
+    /// `suffix` backup belonged to; same default as the main command.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub state_dir: Option<PathBuf>,
+
+    /// Directory holding the run locks; same default as the main command.
+    #[arg(long, value_name = "DIR", value_parser = clap::value_parser!(PathBuf))]
+    pub lock_dir: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Default)]
 /// How the canonical/original file is chosen inside a duplicate group.
 pub enum OriginalToKeep {
     /// Select the first entry encountered in the JSON document (stable order).
@@ -234,5 +496,106 @@ pub enum OriginalToKeep {
     /// Re-stat every path and keep the file with the oldest modification time.
     Oldest,
     /// Re-stat every path and keep the file with the newest modification time.
+    #[default]
     Newest,
 }
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// The filesystem object that takes the place of each duplicate.
+pub enum LinkType {
+    /// Replace the duplicate with a symbolic link to the original.
+    #[default]
+    Symlink,
+    /// Replace the duplicate with a hard link to the original. Both files must
+    /// live on the same device; cross-device pairs are skipped.
+    Hardlink,
+    /// Replace the duplicate with a copy-on-write clone of the original
+    /// (btrfs, XFS, APFS). The files share extents but stay independent, so
+    /// writing to one never changes the other.
+    Reflink,
+    /// Leave every path in place and ask the kernel to share the duplicate's
+    /// extents with the original (Linux `FIDEDUPERANGE`). The kernel compares
+    /// the bytes itself, so no `*.czkawka-bak` staging is needed.
+    Dedupe,
+    /// Pick per duplicate: a reflink where the filesystem supports it, then a
+    /// hard link when both files share a device, then a symlink.
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// How the path stored inside a new symlink is spelled.
+pub enum SymlinkTarget {
+    /// Use the original's path exactly as the report spells it.
+    #[default]
+    Reported,
+    /// Use the original's canonicalized path, so links never depend on a
+    /// symlinked mount point or directory the report happened to go through.
+    Canonical,
+    /// Use a path relative to the duplicate's (canonicalized) parent directory,
+    /// so links survive moving or remounting a tree as long as both ends move
+    /// together.
+    Relative,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// What happens to a report entry that is already a symlink to a file outside
+/// its group. Symlinks to another member of the group are always re-pointed at
+/// the original (or reported as already deduplicated).
+pub enum ExistingSymlinks {
+    /// Leave the symlink as it is and drop it from its group.
+    #[default]
+    Skip,
+    /// Add the symlink's target to the group as a real member; the symlink
+    /// stays in the group and ends up pointing straight at the original.
+    Resolve,
+    /// Report the symlink and leave its whole group alone.
+    Error,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+/// How replacements left in flight by an interrupted run are resolved.
+pub enum ResumeJournal {
+    /// Complete each replacement: put the link in place and delete the backup.
+    Finish,
+    /// Undo each replacement: put the original duplicate back.
+    Rollback,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// What `recover` does with each orphaned backup.
+pub enum RecoverAction {
+    /// Only list the backup and the state of the path it came from.
+    #[default]
+    Leave,
+    /// Move the backup back to its original path when that path is missing or
+    /// a link; a real file there is never replaced.
+    Restore,
+    /// Delete the backup when its original path still resolves to the same
+    /// bytes (the same tree, for a directory).
+    Delete,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// Where backups of replaced files are staged during a run.
+pub enum Staging {
+    /// Swap the extension for `czkawka-bak[-N]` next to the file. Files that
+    /// differ only in extension share a stem and get numbered names.
+    #[default]
+    Suffix,
+    /// Keep the full name in a hidden sibling,
+    /// `.<name>.czkawka-bak.<run-id>[-N]`.
+    Hidden,
+    /// Move backups into a hidden `.czkawka-staging` directory inside each
+    /// duplicate's directory, removed again once empty.
+    Dir,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+/// What the real copies kept by `--keep-copies` are spread across.
+pub enum SpreadBy {
+    /// Prefer copies that live on different devices (disks).
+    #[default]
+    Device,
+    /// Prefer copies that live under different allow-roots.
+    Root,
+}
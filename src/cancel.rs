@@ -0,0 +1,86 @@
+//! Cooperative cancellation shared by the CLI's signal handler and the
+//! replacement engine. Workers check the token between replacements, so a
+//! cancelled run never stops halfway through swapping a single link.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Asks a running [`crate::replace_duplicates_with_symlinks`] to stop.
+///
+/// Once cancelled, no new duplicate group is started; replacements already in
+/// flight are finished (or, with [`crate::Args::atomic_groups`], their group is
+/// rolled back), a summary is printed, and the run returns an error. Clones
+/// share the same state, so one clone can be handed to a signal handler or
+/// another thread.
+///
+/// # Examples
+/// ```no_run
+/// use czkawka_dupe_to_symlinks::{
+///     Args, CancellationToken, replace_duplicates_with_symlinks, validate_files,
+/// };
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let args = Args {
+///     input_file_path: "/var/reports/czkawka.json".into(),
+///     allow_roots: vec!["/srv/media".into()],
+///     ..Default::default()
+/// };
+///
+/// let cancel = CancellationToken::new();
+/// let watchdog = cancel.clone();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(600));
+///     watchdog.cancel();
+/// });
+///
+/// let files = validate_files(&args.input_file_path)?;
+/// replace_duplicates_with_symlinks(&args, &files, &cancel)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// A token that has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. Calling it more than once has no further effect.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`CancellationToken::cancel`] has been called on this token or
+    /// any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Cancel `token` on the first SIGINT/SIGTERM (Ctrl-C on Windows) and exit
+/// immediately on the second.
+pub(crate) fn install_signal_handler(token: &CancellationToken) {
+    let token = token.clone();
+
+    let installed = ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            eprintln!(
+                "Second signal received; exiting now. Anything left in flight is in the run journal for --resume-journal."
+            );
+            std::process::exit(130);
+        }
+
+        token.cancel();
+        eprintln!(
+            "Cancelling: no new groups will be started and in-flight replacements are being finished or rolled back. Send the signal again to exit immediately."
+        );
+    });
+
+    if let Err(e) = installed {
+        eprintln!("Failed to install the signal handler: {}", e);
+    }
+}
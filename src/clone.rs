@@ -0,0 +1,381 @@
+//! Copy-on-write primitives backing the reflink and extent-dedupe modes. Shared
+//! extents save space without tying the two files together the way links do:
+//! writing to either side only ever changes that side.
+
+use std::path::Path;
+
+/// Create `destination` as a copy-on-write clone of `original`.
+///
+/// `destination` must not exist yet. When the filesystem cannot clone (tmpfs,
+/// ext4, cross-device pairs, ...) the call fails and nothing is left behind.
+pub(crate) fn reflink(original: &Path, destination: &Path) -> Result<(), std::io::Error> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::fd::AsRawFd;
+
+        let source = std::fs::File::open(original)?;
+        let target = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(destination)?;
+
+        // SAFETY: both descriptors are owned by live `File`s for the duration of
+        // the call, and FICLONE takes the source descriptor by value.
+        let rc = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+
+        if rc != 0 {
+            let err = std::io::Error::last_os_error();
+            drop(target);
+            let _ = std::fs::remove_file(destination);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_vendor = "apple")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let source = std::ffi::CString::new(original.as_os_str().as_bytes())?;
+        let target = std::ffi::CString::new(destination.as_os_str().as_bytes())?;
+
+        // SAFETY: both pointers come from NUL-terminated `CString`s that outlive
+        // the call.
+        let rc = unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) };
+
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+    {
+        let _ = (original, destination);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "reflinks are not supported on this platform",
+        ))
+    }
+}
+
+/// What the kernel did with one range of a duplicate.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RangeStatus {
+    /// The range's extents are now shared with the original.
+    Deduped,
+    /// The kernel refused to share the range because its content differs.
+    Differed,
+    /// The filesystem cannot dedupe the range (nor anything after it).
+    Unsupported,
+}
+
+impl RangeStatus {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RangeStatus::Deduped => "shared",
+            RangeStatus::Differed => "differed",
+            RangeStatus::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// The kernel's answer for one `FIDEDUPERANGE` call.
+pub(crate) struct DedupeRange {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) status: RangeStatus,
+}
+
+/// Every range of one extent-dedupe pass over a file, in offset order.
+pub(crate) struct DedupeOutcome {
+    pub(crate) ranges: Vec<DedupeRange>,
+}
+
+impl DedupeOutcome {
+    /// Total length of the ranges that ended with `status`.
+    pub(crate) fn bytes(&self, status: RangeStatus) -> u64 {
+        self.ranges
+            .iter()
+            .filter(|range| range.status == status)
+            .map(|range| range.len)
+            .sum()
+    }
+}
+
+/// Ask the kernel to share the extents of `duplicate` with those of `original`.
+///
+/// Nothing is renamed or unlinked: the kernel compares both ranges itself and
+/// only shares extents whose bytes are identical, so a stale report can never
+/// cause data to be replaced. Each call's range is returned with its result; a
+/// filesystem that refuses the ioctl marks the rest of the file unsupported.
+pub(crate) fn dedupe(original: &Path, duplicate: &Path) -> Result<DedupeOutcome, std::io::Error> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::fd::AsRawFd;
+
+        /// Upper bound for a single request; btrfs caps each call at 16 MiB.
+        const CHUNK_LEN: u64 = 16 * 1024 * 1024;
+        /// `_IOWR(0x94, 54, struct file_dedupe_range)` from `linux/fs.h`.
+        const FIDEDUPERANGE: u32 = 0xC018_9436;
+        const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+        #[repr(C)]
+        struct FileDedupeRangeInfo {
+            dest_fd: i64,
+            dest_offset: u64,
+            bytes_deduped: u64,
+            status: i32,
+            reserved: u32,
+        }
+
+        #[repr(C)]
+        struct FileDedupeRange {
+            src_offset: u64,
+            src_length: u64,
+            dest_count: u16,
+            reserved1: u16,
+            reserved2: u32,
+            info: [FileDedupeRangeInfo; 1],
+        }
+
+        /// Errors with which filesystems (or the VFS) turn the request down.
+        fn is_unsupported(errno: i32) -> bool {
+            [libc::EOPNOTSUPP, libc::EINVAL, libc::ENOTTY, libc::EXDEV].contains(&errno)
+        }
+
+        let source = std::fs::File::open(original)?;
+        // The kernel lets the file's owner dedupe through a read-only
+        // descriptor, which also works on read-only files; others need a
+        // writable one, opened only once the read-only attempt is refused.
+        let mut target = std::fs::File::open(duplicate)?;
+        let mut writable = false;
+
+        let source_len = source.metadata()?.len();
+        let target_len = target.metadata()?.len();
+
+        if source_len != target_len {
+            return Ok(DedupeOutcome {
+                ranges: vec![DedupeRange {
+                    offset: 0,
+                    len: target_len,
+                    status: RangeStatus::Differed,
+                }],
+            });
+        }
+
+        let mut ranges = Vec::new();
+        let mut offset = 0u64;
+
+        while offset < source_len {
+            let length = CHUNK_LEN.min(source_len - offset);
+            let mut request = FileDedupeRange {
+                src_offset: offset,
+                src_length: length,
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                info: [FileDedupeRangeInfo {
+                    dest_fd: target.as_raw_fd() as i64,
+                    dest_offset: offset,
+                    bytes_deduped: 0,
+                    status: 0,
+                    reserved: 0,
+                }],
+            };
+
+            // SAFETY: `request` is a correctly laid out `file_dedupe_range` with
+            // exactly `dest_count` trailing info records, and both descriptors
+            // stay open for the duration of the call.
+            let rc = unsafe {
+                libc::ioctl(
+                    source.as_raw_fd(),
+                    FIDEDUPERANGE as libc::Ioctl,
+                    &mut request as *mut FileDedupeRange,
+                )
+            };
+
+            let errno = if rc != 0 {
+                std::io::Error::last_os_error().raw_os_error()
+            } else if request.info[0].status < 0 {
+                Some(-request.info[0].status)
+            } else {
+                None
+            };
+
+            match errno {
+                Some(errno) if !writable && [libc::EPERM, libc::EACCES].contains(&errno) => {
+                    target = std::fs::OpenOptions::new().write(true).open(duplicate)?;
+                    writable = true;
+                    continue;
+                }
+                Some(errno) if is_unsupported(errno) => {
+                    ranges.push(DedupeRange {
+                        offset,
+                        len: source_len - offset,
+                        status: RangeStatus::Unsupported,
+                    });
+                    break;
+                }
+                Some(errno) => return Err(std::io::Error::from_raw_os_error(errno)),
+                None => {}
+            }
+
+            let info = &request.info[0];
+
+            if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+                ranges.push(DedupeRange {
+                    offset,
+                    len: length,
+                    status: RangeStatus::Differed,
+                });
+                offset += length;
+                continue;
+            }
+
+            if info.bytes_deduped == 0 {
+                return Err(std::io::Error::other(format!(
+                    "kernel made no progress deduplicating at offset {}",
+                    offset
+                )));
+            }
+
+            ranges.push(DedupeRange {
+                offset,
+                len: info.bytes_deduped,
+                status: RangeStatus::Deduped,
+            });
+            offset += info.bytes_deduped;
+        }
+
+        Ok(DedupeOutcome { ranges })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (original, duplicate);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "extent dedupe (FIDEDUPERANGE) is only available on Linux",
+        ))
+    }
+}
+
+/// Whether `a` and `b` already share every extent on disk, as a reflink (or an
+/// earlier extent dedupe) leaves them. Filesystems that cannot report shared
+/// extents (ext4, tmpfs, ...) never match.
+pub fn shares_extents(a: &Path, b: &Path) -> Result<bool, std::io::Error> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let a = shared_extents(a)?;
+        Ok(!a.is_empty() && a == shared_extents(b)?)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (a, b);
+        Ok(false)
+    }
+}
+
+/// `(logical, physical, length)` of every extent of `path`, or nothing when
+/// any extent is not a plain on-disk extent marked as shared.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn shared_extents(path: &Path) -> Result<Vec<(u64, u64, u64)>, std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    /// `_IOWR('f', 11, struct fiemap)` from `linux/fs.h`.
+    const FS_IOC_FIEMAP: u32 = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    /// Extents fetched per call.
+    const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; BATCH],
+    }
+
+    let file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut start = 0u64;
+
+    while start < len {
+        let mut request = Fiemap {
+            fm_start: start,
+            fm_length: len - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: BATCH as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent {
+                fe_logical: 0,
+                fe_physical: 0,
+                fe_length: 0,
+                fe_reserved64: [0; 2],
+                fe_flags: 0,
+                fe_reserved: [0; 3],
+            }; BATCH],
+        };
+
+        // SAFETY: `request` is a correctly laid out `struct fiemap` with room
+        // for `fm_extent_count` trailing extents, and the descriptor stays open
+        // for the duration of the call.
+        let rc = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                FS_IOC_FIEMAP as libc::Ioctl,
+                &mut request as *mut Fiemap,
+            )
+        };
+
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mapped = (request.fm_mapped_extents as usize).min(BATCH);
+        if mapped == 0 {
+            break;
+        }
+
+        for extent in &request.fm_extents[..mapped] {
+            if extent.fe_flags & FIEMAP_EXTENT_SHARED == 0
+                || extent.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) != 0
+            {
+                return Ok(Vec::new());
+            }
+
+            extents.push((extent.fe_logical, extent.fe_physical, extent.fe_length));
+            start = extent.fe_logical + extent.fe_length;
+
+            if extent.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+                return Ok(extents);
+            }
+        }
+    }
+
+    Ok(extents)
+}
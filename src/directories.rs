@@ -0,0 +1,578 @@
+//! Whole-directory deduplication. When two directory trees contain nothing but
+//! files that the reports pair up one-to-one, the duplicate tree is replaced by
+//! a single directory symlink instead of one link per file.
+
+use crate::cancel::CancellationToken;
+use crate::journal::{self, Journal};
+use crate::staging::StagingArea;
+use crate::symlinks::{self, CzkawkaDuplicateJsonFormatElement};
+use crate::{args, rename};
+use anyhow::Error;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Relative file and directory paths found by a live walk of one tree.
+#[derive(PartialEq, Eq)]
+struct TreeListing {
+    files: BTreeSet<PathBuf>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+/// Replace every verified duplicate directory tree with a symlink to its
+/// original tree. Flag compatibility is checked up front by the caller. Stops
+/// before the next directory once `cancel` is set.
+///
+/// Returns the directories that were (or, in a dry run, would be) replaced so
+/// the per-file pass can drop the report entries living inside them.
+pub(crate) fn dedupe_directories(
+    args: &args::Args,
+    staging: &StagingArea,
+    journal: &Journal,
+    groups: &[Vec<CzkawkaDuplicateJsonFormatElement>],
+    cancel: &CancellationToken,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> Vec<PathBuf> {
+    let mut group_of = HashMap::new();
+    let mut entry_of = HashMap::new();
+
+    for (index, group) in groups.iter().enumerate() {
+        if group.len() < 2 || !symlinks::confirm_hashes_match(group) {
+            continue;
+        }
+
+        for entry in group {
+            let path = PathBuf::from(&entry.path);
+            group_of.entry(path.clone()).or_insert(index);
+            entry_of.entry(path).or_insert(entry);
+        }
+    }
+
+    let mut listings = HashMap::new();
+    let verified = candidate_pairs(groups, &group_of)
+        .into_iter()
+        .filter(|(a, b)| !same_directory(a, b))
+        .filter(|(a, b)| trees_match(args, a, b, &group_of, &mut listings))
+        .collect::<BTreeSet<(PathBuf, PathBuf)>>();
+
+    // Keep only the outermost matching trees, and drop pairs nested inside
+    // another pair's trees so no directory is both replaced and a target.
+    let outermost = verified
+        .iter()
+        .filter(|(a, b)| match (a.parent(), b.parent()) {
+            (Some(pa), Some(pb)) => !verified.contains(&ordered(pa, pb)),
+            _ => true,
+        })
+        .cloned()
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    let pairs = outermost
+        .iter()
+        .filter(|(a, b)| {
+            !outermost.iter().any(|(oa, ob)| {
+                [oa, ob].iter().any(|outer| {
+                    (a != *outer && a.starts_with(outer)) || (b != *outer && b.starts_with(outer))
+                })
+            })
+        })
+        .cloned()
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+
+    let mut replaced = Vec::new();
+
+    for class in equivalence_classes(&pairs) {
+        let Some(original_dir) = choose_original_dir(args, &class, &listings, &entry_of) else {
+            continue;
+        };
+
+        for duplicate_dir in class.iter().filter(|dir| **dir != original_dir) {
+            if cancel.is_cancelled() {
+                return replaced;
+            }
+
+            let file_count = listings
+                .get(duplicate_dir)
+                .and_then(|listing| listing.as_ref())
+                .map_or(0, |listing| listing.files.len());
+
+            if replace_directory(
+                args,
+                staging,
+                journal,
+                &original_dir,
+                duplicate_dir,
+                file_count,
+                errors,
+            ) {
+                replaced.push(duplicate_dir.clone());
+            }
+        }
+    }
+
+    replaced
+}
+
+fn ordered(a: &Path, b: &Path) -> (PathBuf, PathBuf) {
+    if a <= b {
+        (a.to_path_buf(), b.to_path_buf())
+    } else {
+        (b.to_path_buf(), a.to_path_buf())
+    }
+}
+
+/// A bind mount or symlinked directory shows one tree under two paths;
+/// replacing either path would delete the only copy. Pairs that cannot be
+/// checked are treated as the same directory.
+fn same_directory(a: &Path, b: &Path) -> bool {
+    match symlinks::same_file(a, b) {
+        Ok(false) => false,
+        Ok(true) => {
+            println!(
+                "Skipped directory '{}': it is the same directory as '{}'",
+                b.display(),
+                a.display()
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "Skipped directories '{}' and '{}': failed to compare them: {}",
+                a.display(),
+                b.display(),
+                e
+            );
+            true
+        }
+    }
+}
+
+/// Directory pairs that could hold the same tree: for every two members of a
+/// group with the same file name, each pair of ancestors reached by walking up
+/// while the directory names keep matching.
+fn candidate_pairs(
+    groups: &[Vec<CzkawkaDuplicateJsonFormatElement>],
+    group_of: &HashMap<PathBuf, usize>,
+) -> BTreeSet<(PathBuf, PathBuf)> {
+    let mut candidates = BTreeSet::new();
+
+    for (index, group) in groups.iter().enumerate() {
+        let members = group
+            .iter()
+            .map(|entry| PathBuf::from(&entry.path))
+            .filter(|path| group_of.get(path) == Some(&index))
+            .collect::<Vec<PathBuf>>();
+
+        for (i, p) in members.iter().enumerate() {
+            for q in &members[i + 1..] {
+                if p.file_name() != q.file_name() {
+                    continue;
+                }
+
+                let (mut a, mut b) = (p.parent(), q.parent());
+
+                while let (Some(da), Some(db)) = (a, b) {
+                    if da.as_os_str().is_empty()
+                        || db.as_os_str().is_empty()
+                        || da.starts_with(db)
+                        || db.starts_with(da)
+                    {
+                        break;
+                    }
+
+                    candidates.insert(ordered(da, db));
+
+                    if da.file_name().is_none() || da.file_name() != db.file_name() {
+                        break;
+                    }
+
+                    a = da.parent();
+                    b = db.parent();
+                }
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Walk both trees live and confirm they hold exactly the same relative
+/// paths, nothing but regular files and directories, and that every file pair
+/// sits in the same duplicate group.
+fn trees_match(
+    args: &args::Args,
+    a: &Path,
+    b: &Path,
+    group_of: &HashMap<PathBuf, usize>,
+    listings: &mut HashMap<PathBuf, Option<TreeListing>>,
+) -> bool {
+    for dir in [a, b] {
+        let Ok(canonical) =
+            symlinks::ensure_path_within_roots(&dir.to_string_lossy(), &args.allow_roots)
+        else {
+            return false;
+        };
+
+        // Never swap out an allow-root itself.
+        if args.allow_roots.contains(&canonical) {
+            return false;
+        }
+
+        if !listings.contains_key(dir) {
+            listings.insert(dir.to_path_buf(), list_tree(dir));
+        }
+    }
+
+    let (Some(Some(listing_a)), Some(Some(listing_b))) = (listings.get(a), listings.get(b)) else {
+        return false;
+    };
+
+    !listing_a.files.is_empty()
+        && listing_a == listing_b
+        && listing_a.files.iter().all(|relative| {
+            match (
+                group_of.get(&a.join(relative)),
+                group_of.get(&b.join(relative)),
+            ) {
+                (Some(ga), Some(gb)) => ga == gb,
+                _ => false,
+            }
+        })
+}
+
+/// List a tree without following links. Returns `None` if anything other than
+/// regular files and directories is found or the walk fails.
+fn list_tree(dir: &Path) -> Option<TreeListing> {
+    let mut listing = TreeListing {
+        files: BTreeSet::new(),
+        dirs: BTreeSet::new(),
+    };
+
+    if !std::fs::symlink_metadata(dir).ok()?.is_dir() {
+        return None;
+    }
+
+    for entry in walkdir::WalkDir::new(dir).min_depth(1) {
+        let entry = entry.ok()?;
+        let relative = entry.path().strip_prefix(dir).ok()?.to_path_buf();
+        let file_type = entry.file_type();
+
+        if file_type.is_file() {
+            listing.files.insert(relative);
+        } else if file_type.is_dir() {
+            listing.dirs.insert(relative);
+        } else {
+            return None;
+        }
+    }
+
+    Some(listing)
+}
+
+/// Merge overlapping pairs into sets of directories that all hold the same
+/// tree.
+fn equivalence_classes(pairs: &[(PathBuf, PathBuf)]) -> Vec<Vec<PathBuf>> {
+    let mut classes: Vec<BTreeSet<PathBuf>> = Vec::new();
+
+    for (a, b) in pairs {
+        let touching = classes
+            .iter()
+            .enumerate()
+            .filter(|(_, class)| class.contains(a) || class.contains(b))
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+
+        let mut merged = BTreeSet::from([a.clone(), b.clone()]);
+        for index in touching.into_iter().rev() {
+            merged.extend(classes.remove(index));
+        }
+        classes.push(merged);
+    }
+
+    classes
+        .into_iter()
+        .map(|class| class.into_iter().collect())
+        .collect()
+}
+
+/// Apply `--original-to-keep` to one representative file of the tree and keep
+/// the directory that holds the winner.
+fn choose_original_dir(
+    args: &args::Args,
+    class: &[PathBuf],
+    listings: &HashMap<PathBuf, Option<TreeListing>>,
+    entry_of: &HashMap<PathBuf, &CzkawkaDuplicateJsonFormatElement>,
+) -> Option<PathBuf> {
+    let representative = listings
+        .get(class.first()?)?
+        .as_ref()?
+        .files
+        .iter()
+        .next()?;
+
+    let entries = class
+        .iter()
+        .map(|dir| {
+            entry_of
+                .get(&dir.join(representative))
+                .map(|e| (*e).clone())
+        })
+        .collect::<Option<Vec<CzkawkaDuplicateJsonFormatElement>>>()?;
+
+    let chosen = Path::new(&symlinks::choose_original_file(args, &entries).path).to_path_buf();
+
+    class
+        .iter()
+        .find(|dir| dir.join(representative) == chosen)
+        .cloned()
+}
+
+/// Swap a journaled symlink to `original_dir` in for `duplicate_dir`, then
+/// delete the replaced tree. Returns whether the directory was (or would be)
+/// replaced.
+fn replace_directory(
+    args: &args::Args,
+    staging: &StagingArea,
+    journal: &Journal,
+    original_dir: &Path,
+    duplicate_dir: &Path,
+    file_count: usize,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> bool {
+    let target =
+        match symlinks::symlink_target(args.symlink_target, original_dir, None, duplicate_dir) {
+            Ok(target) => target,
+            Err(e) => {
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Failed to compute symlink target from directory '{}' to '{}': {}",
+                        duplicate_dir.display(),
+                        original_dir.display(),
+                        e
+                    ));
+                return false;
+            }
+        };
+
+    if args.dry_run {
+        println!(
+            "[Dry Run] Would replace directory '{}' ({} files) with symlink to '{}'",
+            duplicate_dir.display(),
+            file_count,
+            target.display()
+        );
+        return true;
+    }
+
+    let (backup_path, entry) = match swap_in_dir_symlink(
+        staging,
+        journal,
+        args.durable,
+        original_dir,
+        &target,
+        duplicate_dir,
+    ) {
+        Ok(swapped) => swapped,
+        Err(e) => {
+            eprintln!(
+                "Failed to replace directory '{}' with a symlink to '{}': {}",
+                duplicate_dir.display(),
+                target.display(),
+                e
+            );
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(anyhow::anyhow!(
+                    "Failed to replace directory '{}' with a symlink to '{}': {}",
+                    duplicate_dir.display(),
+                    target.display(),
+                    e
+                ));
+            return false;
+        }
+    };
+
+    if args.durable
+        && let Err(e) = rename::sync_parents(&[duplicate_dir, &backup_path])
+    {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Symlinked directory '{}' but kept backup '{}' because the symlink could not be made durable: {}",
+                duplicate_dir.display(),
+                backup_path.display(),
+                e
+            ));
+    } else if let Err(e) = journal.commit(entry) {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Symlinked directory '{}' but kept backup '{}' because the journal could not be written: {}",
+                duplicate_dir.display(),
+                backup_path.display(),
+                e
+            ));
+    } else if let Err(e) = std::fs::remove_dir_all(&backup_path) {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Symlinked directory '{}' but failed to delete backup '{}': {}",
+                duplicate_dir.display(),
+                backup_path.display(),
+                e
+            ));
+    } else if args.durable
+        && let Err(e) = rename::sync_parents(&[&backup_path])
+    {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Symlinked directory '{}' but the removal of backup '{}' could not be made durable: {}",
+                duplicate_dir.display(),
+                backup_path.display(),
+                e
+            ));
+    } else if let Err(e) = journal.done(entry) {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Symlinked directory '{}' but failed to record it in the journal: {}",
+                duplicate_dir.display(),
+                e
+            ));
+    }
+
+    println!(
+        "Replaced directory '{}' ({} files) with symlink to '{}'",
+        duplicate_dir.display(),
+        file_count,
+        target.display()
+    );
+
+    true
+}
+
+/// Create a directory symlink at a journaled staging name and swap it with
+/// `duplicate_dir`, like files are swapped. Without an atomic exchange the tree
+/// is moved to a journaled backup name first and the link renamed in after it.
+/// Returns where the replaced tree now lives and the journal entry.
+fn swap_in_dir_symlink(
+    staging: &StagingArea,
+    journal: &Journal,
+    durable: bool,
+    original_dir: &Path,
+    target: &Path,
+    duplicate_dir: &Path,
+) -> Result<(PathBuf, u64), std::io::Error> {
+    let mut counter = 0;
+
+    let (staged_link, entry) = loop {
+        let staged_link = staging.free_name(duplicate_dir, &mut counter)?;
+        let entry = journal.stage(
+            duplicate_dir,
+            original_dir,
+            journal::DIRECTORY_SYMLINK,
+            Some(target),
+            &staged_link,
+        )?;
+
+        match create_dir_symlink(target, &staged_link) {
+            Ok(()) => break (staged_link, entry),
+            Err(e) => {
+                let _ = journal.abandoned(entry);
+                // Another worker took the name after it was found free.
+                if e.kind() != std::io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+                counter += 1;
+            }
+        }
+    };
+
+    let abandon = |e: std::io::Error| {
+        let _ = remove_dir_symlink(&staged_link);
+        let _ = journal.abandoned(entry);
+        Err(e)
+    };
+
+    if durable && let Err(e) = rename::sync_parents(&[&staged_link, duplicate_dir]) {
+        return abandon(e);
+    }
+
+    if let Err(e) = journal.linked(
+        entry,
+        journal::DIRECTORY_SYMLINK,
+        Some(target),
+        &staged_link,
+    ) {
+        return abandon(e);
+    }
+
+    match rename::exchange(&staged_link, duplicate_dir) {
+        Ok(()) => return Ok((staged_link, entry)),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {}
+        Err(e) => return abandon(e),
+    }
+
+    let backup_path = loop {
+        let backup_path = match staging.free_name(duplicate_dir, &mut counter) {
+            Ok(backup_path) => backup_path,
+            Err(e) => return abandon(e),
+        };
+
+        if let Err(e) = journal.backup(entry, &backup_path) {
+            return abandon(e);
+        }
+
+        match rename::rename_noreplace(duplicate_dir, &backup_path) {
+            Ok(()) => break backup_path,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return abandon(e),
+        }
+    };
+
+    if let Err(e) = std::fs::rename(&staged_link, duplicate_dir) {
+        if std::fs::rename(&backup_path, duplicate_dir).is_err() {
+            // The entry stays in flight for `--resume-journal`, which finds
+            // the tree under its journaled backup name.
+            return Err(std::io::Error::other(format!(
+                "{}; the directory is still at '{}'",
+                e,
+                backup_path.display()
+            )));
+        }
+        return abandon(e);
+    }
+
+    Ok((backup_path, entry))
+}
+
+fn create_dir_symlink(target: &Path, link_path: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        std::os::unix::fs::symlink(target, link_path)
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        std::os::windows::fs::symlink_dir(target, link_path)
+    }
+}
+
+/// Remove a link made by `create_dir_symlink`.
+pub(crate) fn remove_dir_symlink(link_path: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        std::fs::remove_file(link_path)
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        std::fs::remove_dir(link_path)
+    }
+}
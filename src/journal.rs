@@ -0,0 +1,1328 @@
+//! Write-ahead journal for in-place replacements. Every run that changes the
+//! filesystem appends one JSON record per step to
+//! `<state-dir>/journals/<run-id>.jsonl` and fsyncs it before the step happens,
+//! so a run killed at any point can be finished or rolled back on the next
+//! start.
+
+use crate::cancel::CancellationToken;
+use crate::{args, directories, lock, rename, symlinks, undo};
+use anyhow::{Context, Error, Result};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Identity of a directory entry that survives renames, used to tell which
+/// name holds the old duplicate and which holds the new link after a crash.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+/// Identity of `path` itself (links are not followed), or `None` where the
+/// platform does not expose one.
+pub(crate) fn file_id(path: &Path) -> Result<Option<FileId>, std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::symlink_metadata(path)?;
+        Ok(Some(FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }))
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        std::fs::symlink_metadata(path)?;
+        Ok(None)
+    }
+}
+
+/// Identity of the file `path` resolves to (links are followed), or `None`
+/// where the platform does not expose one. Two paths with the same identity
+/// are one file, whether through hard links, symlinks or bind mounts.
+pub(crate) fn resolved_file_id(path: &Path) -> Result<Option<FileId>, std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata(path)?;
+        Ok(Some(FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }))
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        std::fs::metadata(path)?;
+        Ok(None)
+    }
+}
+
+/// Link type recorded for a `--dedupe-directories` replacement. Its size and
+/// BLAKE3 cover the whole tree, as [`tree_digest`] computes them.
+pub(crate) const DIRECTORY_SYMLINK: &str = "directory symlink";
+
+/// Total file size and BLAKE3 of the tree at `dir`: every entry's relative
+/// path in walk order, plus each file's length and content. Two trees with the
+/// same digest hold the same names and bytes. Anything but files and
+/// directories is refused.
+pub(crate) fn tree_digest(dir: &Path) -> Result<(u64, String), std::io::Error> {
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0;
+
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            hasher.update(b"d\0");
+            hasher.update(relative.as_os_str().as_encoded_bytes());
+            hasher.update(b"\0");
+        } else if file_type.is_file() {
+            let mut file = std::fs::File::open(entry.path())?;
+            let len = file.metadata()?.len();
+            hasher.update(b"f\0");
+            hasher.update(relative.as_os_str().as_encoded_bytes());
+            hasher.update(b"\0");
+            hasher.update(&len.to_le_bytes());
+            size += std::io::copy(&mut file, &mut hasher)?;
+        } else {
+            return Err(std::io::Error::other(format!(
+                "'{}' is neither a file nor a directory",
+                entry.path().display()
+            )));
+        }
+    }
+
+    Ok((size, hasher.finalize().to_hex().to_string()))
+}
+
+/// One line of the journal.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    /// First line of every journal.
+    Begin {
+        run_id: String,
+        pid: u32,
+        started: u64,
+        /// The allow-roots the run locked.
+        roots: Vec<PathBuf>,
+        /// Where it locked them; `None` for a run that took no lock.
+        lock_dir: Option<PathBuf>,
+    },
+    /// The new link for `duplicate` is about to be created at `staged_link`.
+    Stage(Box<StageRecord>),
+    /// The link now exists at the staged name and is about to be swapped with
+    /// the duplicate.
+    Linked {
+        entry: u64,
+        link_type: String,
+        link_target: Option<PathBuf>,
+        link_id: Option<FileId>,
+    },
+    /// Exchange was unavailable; the old duplicate is about to get a second
+    /// name at `backup`, and then the link is renamed over `duplicate`.
+    Backup { entry: u64, backup: PathBuf },
+    /// The swap succeeded and the backup is about to be deleted.
+    Commit { entry: u64 },
+    /// The backup is gone; nothing is left to do for this entry.
+    Done { entry: u64 },
+    /// The backup is about to be renamed back over the link.
+    Restore { entry: u64 },
+    /// The duplicate is back in place.
+    Restored { entry: u64 },
+    /// The swap failed before touching the duplicate and was cleaned up.
+    Abandoned { entry: u64 },
+    /// The run (or a later recovery) finished with this journal.
+    End,
+    /// `undo` turned the link back into a real copy.
+    Undone { entry: u64 },
+}
+
+/// Payload of [`Record::Stage`], boxed to keep the other records small.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StageRecord {
+    entry: u64,
+    duplicate: PathBuf,
+    original: PathBuf,
+    /// The requested link type; [`Record::Linked`] has the one created.
+    link_type: String,
+    staged_link: PathBuf,
+    duplicate_id: Option<FileId>,
+    /// What the new symlink will contain, if one is created.
+    #[serde(default)]
+    link_target: Option<PathBuf>,
+    #[serde(default)]
+    original_id: Option<FileId>,
+    #[serde(default)]
+    original_size: Option<u64>,
+    /// The original file's modification time when the link was made, a
+    /// cheap first check for `undo` before it compares content.
+    #[serde(default)]
+    original_modified: Option<std::time::SystemTime>,
+    /// BLAKE3 of the original's content (for a directory, of its tree) when
+    /// the link was made.
+    #[serde(default)]
+    original_blake3: Option<String>,
+}
+
+/// The journal of the current run. A disabled journal (dry runs, or library
+/// callers without a state directory) accepts every call and writes nothing.
+pub(crate) struct Journal {
+    file: Option<std::sync::Mutex<std::fs::File>>,
+    /// Where an open run's journal lives; it is moved to `done/` by
+    /// [`Journal::end`].
+    open_path: Option<PathBuf>,
+    next_entry: std::sync::atomic::AtomicU64,
+    /// Size and BLAKE3 of each original already hashed by this run, or taken
+    /// from the report.
+    digests: std::sync::Mutex<std::collections::HashMap<PathBuf, (u64, String)>>,
+}
+
+impl Journal {
+    pub(crate) fn disabled() -> Self {
+        Self {
+            file: None,
+            open_path: None,
+            next_entry: std::sync::atomic::AtomicU64::new(0),
+            digests: Default::default(),
+        }
+    }
+
+    /// Start the journal for `run_id`, which locked `roots` in `lock_dir`,
+    /// under `state_dir`.
+    pub(crate) fn create(
+        state_dir: &Path,
+        run_id: &str,
+        roots: &[PathBuf],
+        lock_dir: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let dir = journals_dir(state_dir);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create journal directory '{}'.", dir.display()))?;
+
+        let path = dir.join(format!("{}.jsonl", run_id));
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create journal '{}'.", path.display()))?;
+
+        let journal = Self {
+            file: Some(std::sync::Mutex::new(file)),
+            open_path: Some(path.clone()),
+            next_entry: std::sync::atomic::AtomicU64::new(0),
+            digests: Default::default(),
+        };
+
+        journal
+            .append(&Record::Begin {
+                run_id: run_id.to_string(),
+                pid: std::process::id(),
+                started: unix_seconds(),
+                roots: roots.to_vec(),
+                lock_dir: lock_dir.map(Path::to_path_buf),
+            })
+            .with_context(|| format!("Failed to write journal '{}'.", path.display()))?;
+
+        // Records are synced as they are written; the journal's own directory
+        // entry has to be synced once so recovery can find it after a crash.
+        rename::sync_parents(&[&path])
+            .with_context(|| format!("Failed to sync journal directory '{}'.", dir.display()))?;
+
+        Ok(journal)
+    }
+
+    /// Reopen the journal of a finished run to record `undo` results.
+    pub(crate) fn reopen(state_dir: &Path, run_id: &str) -> Result<Self, Error> {
+        let path = journal_path(state_dir, run_id)?;
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open journal '{}'.", path.display()))?;
+
+        Ok(Self {
+            file: Some(std::sync::Mutex::new(file)),
+            open_path: None,
+            next_entry: std::sync::atomic::AtomicU64::new(0),
+            digests: Default::default(),
+        })
+    }
+
+    /// Record that the link for `duplicate` is about to be created at
+    /// `staged_link`, and return the entry number for the following steps. The
+    /// original's size, modification time and BLAKE3 (for a directory, of its
+    /// tree) are recorded too, so `undo` can verify it later. Each original is
+    /// hashed once per run, and not at all when [`Journal::report_hash`] gave
+    /// its hash.
+    pub(crate) fn stage(
+        &self,
+        duplicate: &Path,
+        original: &Path,
+        link_type: &str,
+        link_target: Option<&Path>,
+        staged_link: &Path,
+    ) -> Result<u64, std::io::Error> {
+        let entry = self
+            .next_entry
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        if self.file.is_none() {
+            return Ok(entry);
+        }
+
+        let (original_size, original_blake3) = self.digest(original, link_type)?;
+        let original_modified = if link_type == DIRECTORY_SYMLINK {
+            None
+        } else {
+            std::fs::metadata(original)?.modified().ok()
+        };
+
+        self.append(&Record::Stage(Box::new(StageRecord {
+            entry,
+            duplicate: duplicate.to_path_buf(),
+            original: original.to_path_buf(),
+            link_type: link_type.to_string(),
+            staged_link: staged_link.to_path_buf(),
+            duplicate_id: file_id(duplicate)?,
+            link_target: link_target.map(Path::to_path_buf),
+            original_id: file_id(original)?,
+            original_size: Some(original_size),
+            original_modified,
+            original_blake3: Some(original_blake3),
+        })))?;
+
+        Ok(entry)
+    }
+
+    /// Take the report's hash for `original`, which holds `size` bytes, as its
+    /// content hash when it is a BLAKE3 digest (64 hex digits, the only
+    /// 256-bit hash Czkawka offers), so [`Journal::stage`] need not read the
+    /// file. A stale report only makes `undo` refuse the entry later.
+    pub(crate) fn report_hash(&self, original: &Path, size: u64, hash: &str) {
+        if self.file.is_none() || hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return;
+        }
+
+        self.digests
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .entry(original.to_path_buf())
+            .or_insert_with(|| (size, hash.to_ascii_lowercase()));
+    }
+
+    fn digest(&self, original: &Path, link_type: &str) -> Result<(u64, String), std::io::Error> {
+        if let Some(known) = self
+            .digests
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .get(original)
+        {
+            return Ok(known.clone());
+        }
+
+        let digest = if link_type == DIRECTORY_SYMLINK {
+            tree_digest(original)?
+        } else {
+            let mut hasher = blake3::Hasher::new();
+            let size = std::io::copy(&mut std::fs::File::open(original)?, &mut hasher)?;
+            (size, hasher.finalize().to_hex().to_string())
+        };
+
+        self.digests
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .insert(original.to_path_buf(), digest.clone());
+
+        Ok(digest)
+    }
+
+    /// Record the link that now exists at `staged_link`, before it is swapped
+    /// in.
+    pub(crate) fn linked(
+        &self,
+        entry: u64,
+        link_type: &str,
+        link_target: Option<&Path>,
+        staged_link: &Path,
+    ) -> Result<(), std::io::Error> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        self.append(&Record::Linked {
+            entry,
+            link_type: link_type.to_string(),
+            link_target: link_target.map(Path::to_path_buf),
+            link_id: file_id(staged_link)?,
+        })
+    }
+
+    pub(crate) fn backup(&self, entry: u64, backup: &Path) -> Result<(), std::io::Error> {
+        self.append(&Record::Backup {
+            entry,
+            backup: backup.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn commit(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Commit { entry })
+    }
+
+    pub(crate) fn done(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Done { entry })
+    }
+
+    pub(crate) fn restore(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Restore { entry })
+    }
+
+    pub(crate) fn restored(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Restored { entry })
+    }
+
+    pub(crate) fn abandoned(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Abandoned { entry })
+    }
+
+    /// Close the journal and move it out of the way of later runs' startup
+    /// scan.
+    pub(crate) fn end(&self) -> Result<(), std::io::Error> {
+        self.append(&Record::End)?;
+
+        match &self.open_path {
+            Some(path) => archive(path),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn undone(&self, entry: u64) -> Result<(), std::io::Error> {
+        self.append(&Record::Undone { entry })
+    }
+
+    fn append(&self, record: &Record) -> Result<(), std::io::Error> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        append_record(
+            &mut file.lock().expect("Should be able to unwrap lock"),
+            record,
+        )
+    }
+}
+
+fn append_record(file: &mut std::fs::File, record: &Record) -> Result<(), std::io::Error> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+fn journals_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("journals")
+}
+
+/// Where closed journals are kept, so startup only reads the open ones.
+fn done_dir(state_dir: &Path) -> PathBuf {
+    journals_dir(state_dir).join("done")
+}
+
+/// Move the closed journal at `path` into `done/` next to it. A journal left
+/// behind by a crash in between is moved by the next run's startup scan.
+fn archive(path: &Path) -> Result<(), std::io::Error> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    let done = dir.join("done");
+
+    std::fs::create_dir_all(&done)?;
+    std::fs::rename(path, done.join(name))
+}
+
+/// Path of the journal for `run_id`, closed or not, refusing ids that could
+/// escape the journal directory.
+fn journal_path(state_dir: &Path, run_id: &str) -> Result<PathBuf, Error> {
+    let is_safe = !run_id.is_empty()
+        && run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !is_safe {
+        anyhow::bail!("'{}' is not a valid run id.", run_id);
+    }
+
+    let name = format!("{}.jsonl", run_id);
+    let done = done_dir(state_dir).join(&name);
+
+    if done.exists() {
+        return Ok(done);
+    }
+
+    Ok(journals_dir(state_dir).join(name))
+}
+
+/// A replacement a finished run completed and that has not been undone yet.
+pub(crate) struct CompletedReplacement {
+    pub(crate) entry: u64,
+    pub(crate) duplicate: PathBuf,
+    pub(crate) original: PathBuf,
+    pub(crate) link_type: String,
+    pub(crate) link_target: Option<PathBuf>,
+    pub(crate) original_id: Option<FileId>,
+    pub(crate) original_size: Option<u64>,
+    pub(crate) original_modified: Option<std::time::SystemTime>,
+    pub(crate) original_blake3: Option<String>,
+}
+
+/// Every replacement of `run_id` that was completed and not undone since.
+/// Unfinished runs are refused; they need `--resume-journal` first.
+pub(crate) fn completed_replacements(
+    state_dir: &Path,
+    run_id: &str,
+) -> Result<Vec<CompletedReplacement>, Error> {
+    let path = journal_path(state_dir, run_id)?;
+    let file = std::fs::File::open(&path)
+        .with_context(|| format!("No journal for run {} at '{}'.", run_id, path.display()))?;
+
+    let mut staged: Vec<(CompletedReplacement, bool)> = Vec::new();
+    let mut ended = false;
+
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read journal '{}'.", path.display()))?;
+
+        let Ok(record) = serde_json::from_str::<Record>(&line) else {
+            continue;
+        };
+
+        match record {
+            Record::Stage(stage) => staged.push((
+                CompletedReplacement {
+                    entry: stage.entry,
+                    duplicate: stage.duplicate,
+                    original: stage.original,
+                    link_type: stage.link_type,
+                    link_target: stage.link_target,
+                    original_id: stage.original_id,
+                    original_size: stage.original_size,
+                    original_modified: stage.original_modified,
+                    original_blake3: stage.original_blake3,
+                },
+                false,
+            )),
+            Record::Linked {
+                entry,
+                link_type,
+                link_target,
+                ..
+            } => {
+                if let Some((replacement, _)) = staged.iter_mut().find(|(e, _)| e.entry == entry) {
+                    replacement.link_type = link_type;
+                    replacement.link_target = link_target;
+                }
+            }
+            Record::Done { entry } => {
+                if let Some((_, done)) = staged.iter_mut().find(|(e, _)| e.entry == entry) {
+                    *done = true;
+                }
+            }
+            Record::Restored { entry } | Record::Abandoned { entry } | Record::Undone { entry } => {
+                staged.retain(|(e, _)| e.entry != entry);
+            }
+            Record::End => ended = true,
+            Record::Begin { .. }
+            | Record::Backup { .. }
+            | Record::Commit { .. }
+            | Record::Restore { .. } => {}
+        }
+    }
+
+    if !ended {
+        anyhow::bail!(
+            "Run {} has not finished; resolve it with --resume-journal before undoing it.",
+            run_id
+        );
+    }
+
+    Ok(staged
+        .into_iter()
+        .filter(|(_, done)| *done)
+        .map(|(replacement, _)| replacement)
+        .collect())
+}
+
+pub(crate) fn unix_seconds() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// A replacement that a previous run started but never finished.
+struct InFlight {
+    entry: u64,
+    duplicate: PathBuf,
+    staged_link: PathBuf,
+    backup: Option<PathBuf>,
+    duplicate_id: Option<FileId>,
+    /// `None` until the link is known to exist.
+    link_id: Option<FileId>,
+    link_target: Option<PathBuf>,
+    /// The requested link type until [`Record::Linked`] names the one made.
+    link_type: String,
+    original: PathBuf,
+    original_id: Option<FileId>,
+    original_size: Option<u64>,
+}
+
+/// A journal without an `end` record.
+struct Unfinished {
+    path: PathBuf,
+    run_id: String,
+    /// The allow-roots the run locked.
+    roots: Vec<PathBuf>,
+    /// Where it locked them, so its lock is checked where it was taken.
+    lock_dir: Option<PathBuf>,
+    in_flight: Vec<InFlight>,
+}
+
+/// Whether the process that wrote an unfinished journal is still around.
+#[derive(PartialEq, Eq)]
+enum Owner {
+    Alive,
+    Gone,
+    /// Its lock could not be checked.
+    Unknown,
+}
+
+/// The part of an unfinished run that falls under this run's allow-roots.
+struct InScope<'a> {
+    run: &'a Unfinished,
+    owner: Owner,
+    in_flight: Vec<&'a InFlight>,
+    /// The owner is gone and every in-flight entry is in scope, so the
+    /// journal may be closed once they are resolved.
+    may_close: bool,
+}
+
+/// Look for journals left behind by interrupted runs and deal with them before
+/// this run touches anything.
+///
+/// Only entries under this run's allow-roots are considered; the rest are left
+/// to a run over their own tree. Without `--resume-journal` the run is refused
+/// while any of them is in flight. With it, each is finished or rolled back
+/// under the interrupted run's own root lock, and a journal is closed once
+/// nothing of it is left in flight.
+pub(crate) fn recover_unfinished(
+    args: &args::Args,
+    state_dir: &Path,
+    cancel: &CancellationToken,
+) -> Result<(), Error> {
+    let unfinished = find_unfinished(state_dir, !args.dry_run)?;
+
+    if unfinished.is_empty() {
+        return Ok(());
+    }
+
+    let runs = unfinished
+        .iter()
+        .filter_map(|run| {
+            let owner = owner(run);
+            if owner == Owner::Alive {
+                return None;
+            }
+
+            let (in_flight, outside): (Vec<&InFlight>, Vec<&InFlight>) =
+                run.in_flight.iter().partition(|in_flight| {
+                    undo::ensure_parent_within_roots(&in_flight.duplicate, &args.allow_roots)
+                        .is_ok()
+                });
+
+            if !outside.is_empty() {
+                println!(
+                    "Left {} replacement(s) of interrupted run {} alone: they are outside this run's allow-roots",
+                    outside.len(),
+                    run.run_id
+                );
+            }
+
+            Some(InScope {
+                may_close: owner == Owner::Gone && outside.is_empty(),
+                run,
+                owner,
+                in_flight,
+            })
+        })
+        .collect::<Vec<InScope>>();
+
+    // Resolving entries of a run that may still be running would race it.
+    let undecidable = runs
+        .iter()
+        .filter(|scoped| scoped.owner == Owner::Unknown && !scoped.in_flight.is_empty())
+        .map(|scoped| {
+            format!(
+                "  - run {} left {} replacement(s) in flight, but whether it is still running cannot be told here (journal '{}')",
+                scoped.run.run_id,
+                scoped.in_flight.len(),
+                scoped.run.path.display()
+            )
+        })
+        .collect::<Vec<String>>();
+
+    if !undecidable.is_empty() {
+        anyhow::bail!(
+            "Found unfinished runs that may still be running; resolve them once they are known to be gone:\n{}",
+            undecidable.join("\n")
+        );
+    }
+
+    let Some(action) = args.resume_journal else {
+        let pending = runs
+            .iter()
+            .filter(|scoped| !scoped.in_flight.is_empty())
+            .map(describe_pending)
+            .collect::<Vec<String>>();
+
+        if pending.is_empty() {
+            // Interrupted between replacements: nothing to decide.
+            for scoped in runs.iter().filter(|scoped| scoped.may_close) {
+                close_journal(args, scoped.run)?;
+            }
+            return Ok(());
+        }
+
+        if args.dry_run {
+            println!(
+                "[Dry Run] Found unfinished runs; a real run would refuse to start until --resume-journal finish|rollback is given:\n{}",
+                pending.join("\n")
+            );
+            return Ok(());
+        }
+
+        anyhow::bail!(
+            "Found unfinished runs; re-run with --resume-journal finish or --resume-journal rollback, limited to some runs with --resume-run <RUN_ID> if they need different actions:\n{}",
+            pending.join("\n")
+        );
+    };
+
+    for run_id in &args.resume_runs {
+        if !runs
+            .iter()
+            .any(|scoped| scoped.run.run_id == *run_id && !scoped.in_flight.is_empty())
+        {
+            println!(
+                "--resume-run {}: that run has no replacements in flight under this run's allow-roots",
+                run_id
+            );
+        }
+    }
+
+    let mut failures = Vec::new();
+    let mut not_selected = Vec::new();
+
+    for scoped in &runs {
+        let run = scoped.run;
+
+        if scoped.in_flight.is_empty() {
+            if scoped.may_close {
+                close_journal(args, run)?;
+            }
+            continue;
+        }
+
+        if !args.resume_runs.is_empty() && !args.resume_runs.contains(&run.run_id) {
+            not_selected.push(describe_pending(scoped));
+            continue;
+        }
+
+        // Recovery renames files under the interrupted run's roots, so it
+        // locks them like that run did; a live run over them keeps it out.
+        let _run_lock = match &run.lock_dir {
+            Some(dir) if !args.dry_run => Some(lock::acquire(
+                dir,
+                &run.roots,
+                &run.run_id,
+                args.wait,
+                cancel,
+            )?),
+            _ => None,
+        };
+
+        let mut file = if args.dry_run {
+            None
+        } else {
+            Some(
+                std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&run.path)
+                    .with_context(|| format!("Failed to open journal '{}'.", run.path.display()))?,
+            )
+        };
+
+        let mut run_failures = 0;
+
+        for in_flight in &scoped.in_flight {
+            match recover_entry(args, action, in_flight) {
+                Ok((message, record)) => {
+                    println!("{}", message);
+                    if let Some(file) = &mut file {
+                        append_record(file, &record).with_context(|| {
+                            format!("Failed to write journal '{}'.", run.path.display())
+                        })?;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    failures.push(e);
+                    run_failures += 1;
+                }
+            }
+        }
+
+        if run_failures == 0 && scoped.may_close {
+            close_journal(args, run)?;
+        }
+    }
+
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Could not recover {} journal entr(ies):\n{}",
+            failures.len(),
+            failures
+                .iter()
+                .map(|e| format!("  - {:#}", e))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+    }
+
+    if !not_selected.is_empty() {
+        if args.dry_run {
+            println!(
+                "[Dry Run] Unfinished runs not named by --resume-run remain; a real run would refuse to start:\n{}",
+                not_selected.join("\n")
+            );
+            return Ok(());
+        }
+
+        anyhow::bail!(
+            "Unfinished runs not named by --resume-run remain; resolve them with --resume-journal finish|rollback --resume-run <RUN_ID>:\n{}",
+            not_selected.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// The in-flight entries of `scoped`, one line each, for the messages that
+/// ask which action to take.
+fn describe_pending(scoped: &InScope) -> String {
+    let mut lines = vec![format!(
+        "  - run {} (journal '{}'):",
+        scoped.run.run_id,
+        scoped.run.path.display()
+    )];
+
+    for in_flight in &scoped.in_flight {
+        let backup = in_flight
+            .backup
+            .as_ref()
+            .map(|backup| format!(", old file at '{}'", backup.display()))
+            .unwrap_or_default();
+
+        lines.push(format!(
+            "      entry {}: '{}', link staged as '{}'{}",
+            in_flight.entry,
+            in_flight.duplicate.display(),
+            in_flight.staged_link.display(),
+            backup
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn close_journal(args: &args::Args, run: &Unfinished) -> Result<(), Error> {
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&run.path)
+        .with_context(|| format!("Failed to open journal '{}'.", run.path.display()))?;
+    append_record(&mut file, &Record::End)
+        .with_context(|| format!("Failed to write journal '{}'.", run.path.display()))?;
+    drop(file);
+    archive(&run.path)
+        .with_context(|| format!("Failed to move journal '{}' aside.", run.path.display()))?;
+
+    println!("Closed journal of interrupted run {}", run.run_id);
+
+    Ok(())
+}
+
+/// Replay the open journals. Closed ones still among them (their run crashed
+/// before moving them) are moved to `done/` when `archive_closed` is set.
+fn find_unfinished(state_dir: &Path, archive_closed: bool) -> Result<Vec<Unfinished>, Error> {
+    let mut unfinished = Vec::new();
+
+    for path in journal_files(&journals_dir(state_dir))? {
+        match read_unfinished(&path)? {
+            Some(run) => unfinished.push(run),
+            None if archive_closed => archive(&path)
+                .with_context(|| format!("Failed to move journal '{}' aside.", path.display()))?,
+            None => {}
+        }
+    }
+
+    unfinished.sort_by(|a, b| a.run_id.cmp(&b.run_id));
+
+    Ok(unfinished)
+}
+
+/// Every `*.jsonl` file in `dir`; none if it does not exist.
+fn journal_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read journal directory '{}'.", dir.display()));
+        }
+    };
+
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read journal directory '{}'.", dir.display()))?
+            .path();
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl")
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Map every staging name any journal used to the duplicate it was staged
+/// for. `recover` uses it where a backup's name alone does not say which file
+/// it came from.
+pub(crate) fn staged_names(
+    state_dir: &Path,
+) -> Result<std::collections::HashMap<PathBuf, PathBuf>, Error> {
+    let mut names = std::collections::HashMap::new();
+
+    let mut paths = journal_files(&journals_dir(state_dir))?;
+    paths.extend(journal_files(&done_dir(state_dir))?);
+
+    for path in paths {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open journal '{}'.", path.display()))?;
+        let mut duplicates = std::collections::HashMap::new();
+
+        for line in std::io::BufReader::new(file).lines() {
+            let line =
+                line.with_context(|| format!("Failed to read journal '{}'.", path.display()))?;
+
+            match serde_json::from_str::<Record>(&line) {
+                Ok(Record::Stage(stage)) => {
+                    duplicates.insert(stage.entry, stage.duplicate.clone());
+                    names.insert(stage.staged_link, stage.duplicate);
+                }
+                Ok(Record::Backup { entry, backup }) => {
+                    if let Some(duplicate) = duplicates.get(&entry) {
+                        names.insert(backup, duplicate.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Replay one journal. Returns `None` when it was closed properly.
+fn read_unfinished(path: &Path) -> Result<Option<Unfinished>, Error> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open journal '{}'.", path.display()))?;
+
+    let run_id = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut roots = Vec::new();
+    let mut lock_dir = None;
+    let mut entries: Vec<InFlight> = Vec::new();
+
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read journal '{}'.", path.display()))?;
+
+        // A crash can cut the last line short; it never describes a step that
+        // already happened.
+        let Ok(record) = serde_json::from_str::<Record>(&line) else {
+            continue;
+        };
+
+        match record {
+            // The file name, not the recorded id, names the run: it is what
+            // the run's lock file is named after.
+            Record::Begin {
+                roots: locked,
+                lock_dir: locked_in,
+                ..
+            } => {
+                roots = locked;
+                lock_dir = locked_in;
+            }
+            Record::Stage(stage) => entries.push(InFlight {
+                entry: stage.entry,
+                duplicate: stage.duplicate,
+                staged_link: stage.staged_link,
+                backup: None,
+                duplicate_id: stage.duplicate_id,
+                link_id: None,
+                link_target: stage.link_target,
+                link_type: stage.link_type,
+                original: stage.original,
+                original_id: stage.original_id,
+                original_size: stage.original_size,
+            }),
+            Record::Linked {
+                entry,
+                link_type,
+                link_target,
+                link_id,
+            } => {
+                if let Some(in_flight) = entries.iter_mut().find(|e| e.entry == entry) {
+                    in_flight.link_id = link_id;
+                    in_flight.link_type = link_type;
+                    in_flight.link_target = link_target;
+                }
+            }
+            Record::Backup { entry, backup } => {
+                if let Some(in_flight) = entries.iter_mut().find(|e| e.entry == entry) {
+                    in_flight.backup = Some(backup);
+                }
+            }
+            Record::Done { entry } | Record::Restored { entry } | Record::Abandoned { entry } => {
+                entries.retain(|e| e.entry != entry);
+            }
+            Record::Commit { .. } | Record::Restore { .. } | Record::Undone { .. } => {}
+            Record::End => return Ok(None),
+        }
+    }
+
+    Ok(Some(Unfinished {
+        path: path.to_path_buf(),
+        run_id,
+        roots,
+        lock_dir,
+        in_flight: entries,
+    }))
+}
+
+/// Work out whether the run behind `run` is still going. Runs hold their root
+/// lock in the lock directory they recorded until their journal is closed, so
+/// a lock nobody holds there (or a lock directory that no longer exists, as
+/// after a reboot clears `/run/lock`) proves the run is gone. A lock that
+/// cannot be checked, or a run that took none, could still be running.
+fn owner(run: &Unfinished) -> Owner {
+    match run
+        .lock_dir
+        .as_deref()
+        .map(|dir| lock::is_held(dir, &run.run_id))
+    {
+        Some(Ok(true)) => Owner::Alive,
+        Some(Ok(false)) => Owner::Gone,
+        Some(Err(_)) | None => Owner::Unknown,
+    }
+}
+
+/// Finish or roll back one in-flight entry, working out from file identities
+/// whether the swap had already happened.
+fn recover_entry(
+    args: &args::Args,
+    action: args::ResumeJournal,
+    in_flight: &InFlight,
+) -> Result<(String, Record), Error> {
+    let duplicate = &in_flight.duplicate;
+    let Some(old_id) = in_flight.duplicate_id else {
+        anyhow::bail!(
+            "Cannot verify '{}' on this platform; check it and '{}' by hand.",
+            duplicate.display(),
+            in_flight.staged_link.display()
+        );
+    };
+
+    let id_of = |path: &Path| file_id(path).ok().flatten();
+    let current = id_of(duplicate);
+    let staged = id_of(&in_flight.staged_link);
+    let backup = in_flight
+        .backup
+        .as_deref()
+        .filter(|path| id_of(path) == Some(old_id));
+
+    let verb = match (action, args.dry_run) {
+        (args::ResumeJournal::Finish, false) => "Finished",
+        (args::ResumeJournal::Rollback, false) => "Rolled back",
+        (args::ResumeJournal::Finish, true) => "[Dry Run] Would finish",
+        (args::ResumeJournal::Rollback, true) => "[Dry Run] Would roll back",
+    };
+    let describe = |what: &str| {
+        format!(
+            "{} interrupted replacement of '{}' ({})",
+            verb,
+            duplicate.display(),
+            what
+        )
+    };
+
+    if current == Some(old_id) {
+        // The swap never happened: the duplicate is untouched. Without a
+        // `linked` record the run stopped while creating the link, which may
+        // never have appeared; a leftover is only removed if it is the link.
+        let link_waiting = in_flight.link_id.is_some() && staged == in_flight.link_id;
+        let unconfirmed_link = in_flight.link_id.is_none() && staged.is_some();
+
+        if unconfirmed_link && !is_planned_link(in_flight) {
+            anyhow::bail!(
+                "'{}' is not the link the interrupted run was creating for '{}'; left both alone.",
+                in_flight.staged_link.display(),
+                duplicate.display()
+            );
+        }
+
+        if !args.dry_run {
+            if unconfirmed_link {
+                remove_leftover(&in_flight.staged_link)?;
+            }
+
+            if action == args::ResumeJournal::Finish && link_waiting {
+                move_over(&in_flight.staged_link, duplicate).with_context(|| {
+                    format!(
+                        "Failed to move link '{}' over '{}'.",
+                        in_flight.staged_link.display(),
+                        duplicate.display()
+                    )
+                })?;
+            } else if link_waiting {
+                remove_leftover(&in_flight.staged_link)?;
+            }
+
+            if let Some(backup) = backup {
+                remove_leftover(backup)?;
+            }
+        }
+
+        let record = if action == args::ResumeJournal::Finish && link_waiting {
+            Record::Done {
+                entry: in_flight.entry,
+            }
+        } else {
+            Record::Abandoned {
+                entry: in_flight.entry,
+            }
+        };
+        let what = match &record {
+            Record::Done { .. } => "link moved into place",
+            _ => "duplicate was never replaced",
+        };
+
+        return Ok((describe(what), record));
+    }
+
+    if in_flight.link_id.is_some() && current == in_flight.link_id {
+        // The link is in place; the old duplicate waits under a backup name.
+        let holder = if staged == Some(old_id) {
+            Some(in_flight.staged_link.as_path())
+        } else {
+            backup
+        };
+
+        return match (action, holder) {
+            (args::ResumeJournal::Finish, holder) => {
+                // The holder is the last copy of the replaced file; it goes
+                // only while the link still leads to the data it stood for.
+                if let Some(holder) = holder
+                    && !link_serves_original(in_flight)
+                {
+                    anyhow::bail!(
+                        "Cannot finish '{}': it no longer leads to the original '{}' recorded by the interrupted run; left the replaced file at '{}'.",
+                        duplicate.display(),
+                        in_flight.original.display(),
+                        holder.display()
+                    );
+                }
+
+                if !args.dry_run
+                    && let Some(holder) = holder
+                {
+                    remove_leftover(holder)?;
+                }
+                Ok((
+                    describe("backup removed"),
+                    Record::Done {
+                        entry: in_flight.entry,
+                    },
+                ))
+            }
+            (args::ResumeJournal::Rollback, Some(holder)) => {
+                if !args.dry_run {
+                    move_over(holder, duplicate).with_context(|| {
+                        format!(
+                            "Failed to restore '{}' from '{}'.",
+                            duplicate.display(),
+                            holder.display()
+                        )
+                    })?;
+                }
+                Ok((
+                    describe("restored from backup"),
+                    Record::Restored {
+                        entry: in_flight.entry,
+                    },
+                ))
+            }
+            (args::ResumeJournal::Rollback, None) => anyhow::bail!(
+                "Cannot roll back '{}': its backup is gone.",
+                duplicate.display()
+            ),
+        };
+    }
+
+    if current.is_none()
+        && let Some(backup) = backup
+    {
+        // A directory was moved aside and its link not yet moved in.
+        let link_waiting = in_flight.link_id.is_some() && staged == in_flight.link_id;
+        let finish = action == args::ResumeJournal::Finish && link_waiting;
+
+        if !args.dry_run {
+            let (from, leftover) = if finish {
+                (in_flight.staged_link.as_path(), Some(backup))
+            } else {
+                (
+                    backup,
+                    link_waiting.then_some(in_flight.staged_link.as_path()),
+                )
+            };
+
+            std::fs::rename(from, duplicate).with_context(|| {
+                format!(
+                    "Failed to move '{}' to '{}'.",
+                    from.display(),
+                    duplicate.display()
+                )
+            })?;
+
+            if let Some(leftover) = leftover {
+                remove_leftover(leftover)?;
+            }
+        }
+
+        return Ok(if finish {
+            (
+                describe("link moved into place"),
+                Record::Done {
+                    entry: in_flight.entry,
+                },
+            )
+        } else {
+            (
+                describe("restored from backup"),
+                Record::Restored {
+                    entry: in_flight.entry,
+                },
+            )
+        });
+    }
+
+    anyhow::bail!(
+        "'{}' changed since the interrupted run; left it and '{}' alone.",
+        duplicate.display(),
+        in_flight.staged_link.display()
+    )
+}
+
+/// Rename `from` over `to`. A directory and a link cannot be renamed over each
+/// other, so those are exchanged and the displaced entry is removed.
+fn move_over(from: &Path, to: &Path) -> Result<(), Error> {
+    let is_dir = |path: &Path| std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+
+    if is_dir(from) == is_dir(to) || std::fs::symlink_metadata(to).is_err() {
+        return Ok(std::fs::rename(from, to)?);
+    }
+
+    rename::exchange(from, to)?;
+    remove_leftover(from)
+}
+
+/// Whether the staged name holds the link an interrupted run was creating
+/// before it could record the link's identity: a symlink with the planned
+/// target, a hard link to the original, or a clone with the original's bytes.
+fn is_planned_link(in_flight: &InFlight) -> bool {
+    let path = &in_flight.staged_link;
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+
+    if metadata.is_symlink() {
+        return in_flight.link_target.is_some()
+            && std::fs::read_link(path).ok() == in_flight.link_target;
+    }
+
+    if !metadata.is_file() {
+        return false;
+    }
+
+    if in_flight.original_id.is_some() && file_id(path).ok().flatten() == in_flight.original_id {
+        return true;
+    }
+
+    symlinks::same_content(path, &in_flight.original).unwrap_or(false)
+}
+
+/// Whether the link now at the duplicate's path still resolves to the original
+/// the interrupted run recorded: the same file (device + inode) with the same
+/// size. A reflink is its own copy, so only its size is compared.
+fn link_serves_original(in_flight: &InFlight) -> bool {
+    let Ok(metadata) = std::fs::metadata(&in_flight.duplicate) else {
+        return false;
+    };
+
+    let same_size = metadata.is_dir() || in_flight.original_size == Some(metadata.len());
+
+    if in_flight.link_type == "reflink" {
+        return same_size;
+    }
+
+    let same_file = in_flight.original_id.is_none()
+        || resolved_file_id(&in_flight.duplicate).ok().flatten() == in_flight.original_id;
+
+    same_size && same_file
+}
+
+/// Remove a leftover link or backup. Directories only get here once their
+/// identity matched the replaced tree.
+fn remove_leftover(path: &Path) -> Result<(), Error> {
+    let removed = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(metadata) if metadata.is_symlink() => {
+            std::fs::remove_file(path).or_else(|_| directories::remove_dir_symlink(path))
+        }
+        _ => std::fs::remove_file(path),
+    };
+
+    removed.with_context(|| format!("Failed to remove leftover '{}'.", path.display()))
+}
@@ -19,12 +19,29 @@
 //!     --allow-root /srv/media --allow-root /srv/backups
 //! ```
 //!
-//! Add `--dry-run` to preview changes without touching the filesystem.
+//! Add `--dry-run` to preview changes without touching the filesystem, or
+//! `--link-type hardlink` / `--link-type reflink` to replace duplicates with
+//! hard links or copy-on-write clones instead of symlinks. On Linux,
+//! `--link-type dedupe` leaves every path in place and lets the kernel share
+//! identical extents, while `--link-type auto` picks the best mechanism per
+//! duplicate. `--symlink-target canonical` writes the original's resolved path
+//! into each link, and `--symlink-target relative` writes links relative to the
+//! duplicate's directory so whole trees can be moved or remounted.
+//!
+//! `--vault <DIR>` gives every unique blob one stable home: the chosen original
+//! of each group moves to `<DIR>/blake3/ab/cd/<hash>`, named by a BLAKE3 of its
+//! content, and every member links to it.
+//! `--canonical-dir <PATH>` does the same for a curated tree: originals move
+//! under it (keeping their path relative to their allow-root) unless a member
+//! already lives there. `--keep-copies N --spread-by device` keeps N real files
+//! per group on different disks and links the rest to the nearest kept copy.
+//! `--dedupe-directories` replaces a whole duplicate tree with one directory
+//! symlink once a live walk shows every file in it pairs up with another tree.
 //!
 //! ## Quick start (library)
 //! ```no_run
 //! use czkawka_dupe_to_symlinks::{
-//!     replace_duplicates_with_symlinks, validate_files, Args, OriginalToKeep,
+//!     replace_duplicates_with_symlinks, validate_files, Args, CancellationToken, OriginalToKeep,
 //! };
 //!
 //! # fn main() -> anyhow::Result<()> {
@@ -33,10 +50,11 @@
 //!     dry_run: false,
 //!     original_to_keep: OriginalToKeep::Newest,
 //!     allow_roots: vec!["/srv/media".into(), "/srv/backups".into()],
+//!     ..Default::default()
 //! };
 //!
 //! let files = validate_files(&args.input_file_path)?;
-//! replace_duplicates_with_symlinks(&args, &files)?;
+//! replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())?;
 //! # Ok(())
 //! # }
 //! ```
@@ -55,8 +73,44 @@
 //! ## Safety guardrails
 //! - All file operations are restricted to the canonicalized `--allow-root`
 //!   directories.
-//! - Every replacement stages a `*.czkawka-bak` backup and restores it if the
-//!   symlink cannot be created.
+//! - Every replacement keeps the replaced file as a backup until the link is
+//!   swapped in atomically; `--staging hidden` or `--staging dir` keeps full
+//!   file names and hides backups from folder scans.
+//! - `--atomic-groups` leaves every group either fully deduplicated or exactly
+//!   as it was.
+//! - Each step is journaled (and fsynced) in [`Args::state_dir`] before it
+//!   happens; an interrupted run blocks the next one over the same roots
+//!   until [`Args::resume_journal`] finishes or rolls back what was in flight
+//!   ([`Args::resume_runs`] picks the runs it applies to).
+//! - [`Args::durable`] fsyncs every new link and the directories around it, and
+//!   only deletes a backup once its link is on disk.
+//! - Runs lock their allow-roots in [`Args::lock_dir`], shared by every user
+//!   (else `<state-dir>/locks`), so two runs over overlapping trees never
+//!   stage at the same time ([`Args::wait`] queues).
+//! - Cancelling the [`CancellationToken`] (the CLI does so on SIGINT/SIGTERM)
+//!   stops a run between replacements, never in the middle of one.
+//! - [`undo_run`] turns a finished run's links back into real copies, but only
+//!   where the link and its original still match what the journal recorded.
+//! - [`recover_backups`] finds backups left behind by crashed runs and only
+//!   restores or deletes them when no copy of the data can be lost.
+//! - A path listed in several groups or reports is planned once: groups that
+//!   agree on its hash are merged, and conflicting groups are left alone and
+//!   reported before anything on disk changes.
+//! - Every link points straight at a real file: symlinks in the report are
+//!   never kept as originals, and symlink cycles are reported, not followed.
+//!   Entries that are symlinks to files outside their group are skipped,
+//!   resolved into the group, or rejected per [`Args::existing_symlinks`].
+//! - A member that is the same physical file as the original (same device and
+//!   inode, e.g. through a bind mount or symlinked directory) is never replaced
+//!   by a link to itself; like members that already link to the original, it
+//!   is reported as already deduplicated, so re-applying a report is a no-op.
+//! - Hard links are only attempted when the duplicate and the original live on
+//!   the same device; cross-device pairs are reported as skipped.
+//! - Reflinks fail per duplicate on filesystems without copy-on-write support;
+//!   the clone is attempted under a staging name before the swap, so the
+//!   duplicate is never touched.
+//! - Extent dedupe never renames or unlinks anything; the kernel refuses to
+//!   share ranges whose bytes differ, so the report's hash is only a hint.
 //! - Dry runs (`--dry-run`) exercise the entire pipeline but leave the
 //!   filesystem untouched.
 //!
@@ -65,12 +119,30 @@
 //! |------|---------|
 //! | `0` | All duplicates were processed or intentionally skipped. |
 //! | `1` | At least one duplicate could not be processed (outside sandbox, missing file, permission error, etc.). |
+//! | `130` | The run was cancelled by SIGINT/SIGTERM after finishing or rolling back in-flight replacements. |
 
 mod args;
+mod cancel;
+mod clone;
+mod directories;
+mod journal;
+mod lock;
+mod plan;
+mod recover;
+mod rename;
+mod staging;
 mod symlinks;
+mod undo;
 
-pub use args::{Args, OriginalToKeep, validate_files};
+pub use args::{
+    Args, ExistingSymlinks, LinkType, OriginalToKeep, RecoverAction, RecoverArgs, ResumeJournal,
+    SpreadBy, Staging, SymlinkTarget, UndoArgs, validate_files,
+};
+pub use cancel::CancellationToken;
+pub use clone::shares_extents;
+pub use recover::recover_backups;
 pub use symlinks::replace_duplicates_with_symlinks;
+pub use undo::undo_run;
 
 /// Run the CLI entrypoint.
 ///
@@ -79,6 +151,16 @@ pub use symlinks::replace_duplicates_with_symlinks;
 /// any duplicate fails to process.
 pub fn start() {
     let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).is_some_and(|command| command == "undo") {
+        start_undo(args);
+        return;
+    }
+
+    if args.get(1).is_some_and(|command| command == "recover") {
+        start_recover(args);
+        return;
+    }
     let valid_args = args::validate_arguments(args);
 
     let mut valid_args = match valid_args {
@@ -109,6 +191,19 @@ pub fn start() {
     };
     valid_args.allow_roots = canonical_roots;
 
+    if valid_args.state_dir.is_none() {
+        valid_args.state_dir = args::default_state_dir();
+    }
+    if valid_args.state_dir.is_none() && !valid_args.dry_run {
+        eprintln!(
+            "Cannot work out a state directory for the run journal ($XDG_STATE_HOME and $HOME are unset); pass --state-dir <DIR>."
+        );
+        std::process::exit(2);
+    }
+    if valid_args.lock_dir.is_none() {
+        valid_args.lock_dir = args::default_lock_dir();
+    }
+
     let validated_files = match args::validate_files(&valid_args.input_file_path) {
         Ok(files) => files,
         Err(e) => {
@@ -118,8 +213,87 @@ pub fn start() {
         }
     };
 
-    if let Err(e) = symlinks::replace_duplicates_with_symlinks(&valid_args, &validated_files) {
+    let cancel = CancellationToken::new();
+    cancel::install_signal_handler(&cancel);
+
+    if let Err(e) =
+        symlinks::replace_duplicates_with_symlinks(&valid_args, &validated_files, &cancel)
+    {
         eprintln!("Failed to replace duplicates: {}", e);
+        std::process::exit(if cancel.is_cancelled() { 130 } else { 1 });
+    }
+}
+
+/// Run the `undo` command: everything after the word `undo` is parsed as
+/// [`UndoArgs`].
+fn start_undo(args: Vec<String>) {
+    let mut undo_args = match args::validate_undo_arguments(args.into_iter().skip(1).collect()) {
+        Ok(undo_args) => undo_args,
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    if undo_args.allow_roots.is_empty() {
+        eprintln!(
+            "At least one --allow-root <PATH> must be provided to prevent destructive mistakes."
+        );
+        std::process::exit(2);
+    }
+
+    undo_args.allow_roots = match args::canonicalize_roots(&undo_args.allow_roots) {
+        Ok(roots) => roots,
+        Err(e) => {
+            eprintln!("Failed to validate provided allow-root paths.");
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    if undo_args.state_dir.is_none() {
+        undo_args.state_dir = args::default_state_dir();
+    }
+    if undo_args.lock_dir.is_none() {
+        undo_args.lock_dir = args::default_lock_dir();
+    }
+
+    if let Err(e) = undo::undo_run(&undo_args) {
+        eprintln!("Failed to undo run {}: {}", undo_args.run_id, e);
+        std::process::exit(1);
+    }
+}
+
+/// Run the `recover` command: everything after the word `recover` is parsed
+/// as [`RecoverArgs`].
+fn start_recover(args: Vec<String>) {
+    let mut recover_args =
+        match args::validate_recover_arguments(args.into_iter().skip(1).collect()) {
+            Ok(recover_args) => recover_args,
+            Err(e) => {
+                let _ = e.print();
+                std::process::exit(e.exit_code());
+            }
+        };
+
+    recover_args.roots = match args::canonicalize_roots(&recover_args.roots) {
+        Ok(roots) => roots,
+        Err(e) => {
+            eprintln!("Failed to validate provided root paths.");
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    if recover_args.state_dir.is_none() {
+        recover_args.state_dir = args::default_state_dir();
+    }
+    if recover_args.lock_dir.is_none() {
+        recover_args.lock_dir = args::default_lock_dir();
+    }
+
+    if let Err(e) = recover::recover_backups(&recover_args) {
+        eprintln!("Failed to recover backups: {}", e);
         std::process::exit(1);
     }
 }
@@ -0,0 +1,383 @@
+//! Advisory run locks. Every run that changes files registers its canonical
+//! allow-roots in `<lock-dir>/<run-id>.lock` and holds an exclusive lock on
+//! that file until it exits; a second run whose roots overlap (one contains
+//! the other) refuses to start, or waits with `--wait`. The CLI's lock
+//! directory is shared by every user, so a cron job and a person running as
+//! different users still see each other's locks. A lock directory that is a
+//! symlink, or is owned by another user without being world-writable and
+//! sticky like `/tmp`, is refused before anything in it is read or removed.
+
+use crate::cancel::CancellationToken;
+use crate::journal;
+use anyhow::{Context, Error, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// How often `--wait` re-checks a held lock.
+const WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// What a lock file records about its holder.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Holder {
+    run_id: String,
+    pid: u32,
+    /// Who wrote the lock; `None` where the platform has no user ids.
+    uid: Option<u32>,
+    started: u64,
+    roots: Vec<PathBuf>,
+}
+
+/// A held run lock; released (and its file removed) when dropped.
+pub(crate) struct RunLock {
+    path: PathBuf,
+    _file: std::fs::File,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Where runs lock their roots: `lock_dir` when given (the CLI always gives
+/// one), else `<state_dir>/locks`. `None` means the run goes unlocked, which
+/// only library callers without either can get.
+pub(crate) fn lock_dir(lock_dir: Option<&Path>, state_dir: Option<&Path>) -> Option<PathBuf> {
+    lock_dir
+        .map(Path::to_path_buf)
+        .or_else(|| state_dir.map(|state_dir| state_dir.join("locks")))
+}
+
+/// Lock `roots` for `run_id` in `dir`. An overlapping run that is still alive
+/// makes this fail, or, with `wait`, block until it finishes or `cancel` is
+/// set. Locks left by runs that died are cleared on the way.
+pub(crate) fn acquire(
+    dir: &Path,
+    roots: &[PathBuf],
+    run_id: &str,
+    wait: bool,
+    cancel: &CancellationToken,
+) -> Result<RunLock, Error> {
+    create_shared_dir(dir)
+        .with_context(|| format!("Failed to create lock directory '{}'.", dir.display()))?;
+    check_dir(dir)?;
+    let dir = dir.to_path_buf();
+
+    let roots = roots
+        .iter()
+        .map(|root| std::fs::canonicalize(root).unwrap_or_else(|_| root.clone()))
+        .collect::<Vec<PathBuf>>();
+    let mut announced = false;
+
+    loop {
+        // Only one process at a time may inspect and extend the set of locks,
+        // so two runs starting together cannot both see a free tree.
+        let registry = open_registry(&dir)?;
+        registry
+            .lock()
+            .with_context(|| format!("Failed to lock '{}'.", dir.display()))?;
+
+        let Some((holder, root)) = find_conflict(&dir, &roots)? else {
+            let lock = create_lock(&dir, &roots, run_id)?;
+            drop(registry);
+            return Ok(lock);
+        };
+        drop(registry);
+
+        let description = format!(
+            "run {} (PID {}, started {}) holds overlapping root '{}'",
+            holder.run_id,
+            holder.pid,
+            format_utc(holder.started),
+            root.display()
+        );
+
+        if !wait {
+            anyhow::bail!(
+                "Another {}. Pass --wait to wait for it to finish.",
+                description
+            );
+        }
+
+        if cancel.is_cancelled() {
+            anyhow::bail!("Cancelled while waiting: {}.", description);
+        }
+
+        if !announced {
+            println!("Waiting: {}", description);
+            announced = true;
+        }
+
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Whether a live process holds the lock of `run_id` in `dir`. Runs keep their
+/// lock until they exit, so a missing or unheld lock file means the run is
+/// gone.
+pub(crate) fn is_held(dir: &Path, run_id: &str) -> Result<bool, std::io::Error> {
+    let path = dir.join(format!("{}.lock", run_id));
+
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    match file.try_lock_shared() {
+        Ok(()) => Ok(false),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(true),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Create `dir` so every user can add locks to it. On Unix it gets the mode of
+/// `/tmp` (world-writable, sticky), so nobody can remove another user's lock.
+fn create_shared_dir(dir: &Path) -> Result<(), std::io::Error> {
+    if std::fs::symlink_metadata(dir).is_ok() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(dir)?;
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Whoever created it first has set the mode already.
+        let _ = std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o1777));
+    }
+
+    Ok(())
+}
+
+/// Refuse a lock directory someone else could have planted: runs delete the
+/// unheld lock files in it. It must be a real directory (not a symlink), and
+/// either owned by root or the current user, or world-writable with the sticky
+/// bit, where nobody can remove or replace another user's files.
+fn check_dir(dir: &Path) -> Result<(), Error> {
+    let metadata = std::fs::symlink_metadata(dir)
+        .with_context(|| format!("Failed to inspect lock directory '{}'.", dir.display()))?;
+
+    if !metadata.file_type().is_dir() {
+        anyhow::bail!(
+            "Lock directory '{}' is not a directory (symlinks are refused).",
+            dir.display()
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let shared = metadata.permissions().mode() & 0o1002 == 0o1002;
+
+        if metadata.uid() != 0 && Some(metadata.uid()) != current_uid() && !shared {
+            anyhow::bail!(
+                "Lock directory '{}' is owned by UID {}, not root or the current user, and is not world-writable with the sticky bit; refusing to use it. Remove it or pass another --lock-dir.",
+                dir.display(),
+                metadata.uid()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The effective user id of this process; `None` where there is none.
+fn current_uid() -> Option<u32> {
+    #[cfg(target_family = "unix")]
+    {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        Some(unsafe { libc::geteuid() })
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        None
+    }
+}
+
+/// Who owns the file behind `metadata`; `None` where there are no user ids.
+fn owner_uid(metadata: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(metadata.uid())
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Open the registry file, read-only when it exists: a shared lock works on
+/// any descriptor, and another user may own the file.
+fn open_registry(dir: &Path) -> Result<std::fs::File, Error> {
+    let path = dir.join(".registry");
+
+    let file = match std::fs::File::open(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            // Another user may have created it in between.
+            .or_else(|_| std::fs::File::open(&path)),
+        opened => opened,
+    };
+
+    file.with_context(|| format!("Failed to open '{}'.", path.display()))
+}
+
+/// The first live lock whose roots overlap `roots`. Lock files nobody holds
+/// belong to runs that died and are removed.
+fn find_conflict(dir: &Path, roots: &[PathBuf]) -> Result<Option<(Holder, PathBuf)>, Error> {
+    let mut conflict = None;
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read lock directory '{}'.", dir.display()))?;
+
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read lock directory '{}'.", dir.display()))?
+            .path();
+
+        if path.extension().is_none_or(|extension| extension != "lock") {
+            continue;
+        }
+
+        // Runs only ever create regular files here; anything else was planted
+        // and is neither opened nor removed.
+        let owner = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_file() => owner_uid(&metadata),
+            _ => continue,
+        };
+
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open '{}'.", path.display()));
+            }
+        };
+
+        match file.try_lock_shared() {
+            Ok(()) => {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            Err(std::fs::TryLockError::WouldBlock) if conflict.is_none() => {}
+            // Keep scanning only to clear the remaining stale locks.
+            Err(std::fs::TryLockError::WouldBlock) => continue,
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to check '{}'.", path.display()));
+            }
+        }
+
+        // Holders write their file while still holding the registry lock, so
+        // an unreadable one is damaged; it is treated as covering every root.
+        let holder = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Holder>(&contents).ok());
+
+        let Some(holder) = holder else {
+            let unknown = Holder {
+                run_id: path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                pid: 0,
+                uid: owner,
+                started: 0,
+                roots: Vec::new(),
+            };
+            conflict = roots.first().map(|root| (unknown, root.clone()));
+            continue;
+        };
+
+        // A lock names the user who wrote it; one owned by somebody else
+        // was forged and is neither trusted nor removed.
+        if holder.uid != owner {
+            eprintln!(
+                "Ignored lock file '{}': it claims UID {:?} but is owned by UID {:?}",
+                path.display(),
+                holder.uid,
+                owner
+            );
+            continue;
+        }
+
+        let overlap = roots.iter().find(|root| {
+            holder
+                .roots
+                .iter()
+                .any(|held| root.starts_with(held) || held.starts_with(root))
+        });
+
+        if let Some(root) = overlap {
+            let root = root.clone();
+            conflict = Some((holder, root));
+        }
+    }
+
+    Ok(conflict)
+}
+
+fn create_lock(dir: &Path, roots: &[PathBuf], run_id: &str) -> Result<RunLock, Error> {
+    let path = dir.join(format!("{}.lock", run_id));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create lock file '{}'.", path.display()))?;
+
+    let lock = (|| -> Result<(), std::io::Error> {
+        file.lock()?;
+        let holder = Holder {
+            run_id: run_id.to_string(),
+            pid: std::process::id(),
+            uid: current_uid(),
+            started: journal::unix_seconds(),
+            roots: roots.to_vec(),
+        };
+        file.write_all(&serde_json::to_vec(&holder)?)
+    })();
+
+    if let Err(e) = lock {
+        let _ = std::fs::remove_file(&path);
+        return Err(e).with_context(|| format!("Failed to write lock file '{}'.", path.display()));
+    }
+
+    Ok(RunLock { path, _file: file })
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC` for a Unix timestamp.
+fn format_utc(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+
+    // Days since 1970-01-01 to a proleptic Gregorian date.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
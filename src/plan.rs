@@ -0,0 +1,417 @@
+//! Whole-report planning that runs before anything on disk changes. Groups
+//! arrive from every parsed report at once, so a path listed twice (in one
+//! group, in two groups, or in two reports) is found here instead of being
+//! processed twice by the parallel per-group loop, and the links a run will
+//! create are checked to end at real files rather than at other links.
+
+use crate::symlinks::{CzkawkaDuplicateJsonFormatElement, SameFileKey};
+use anyhow::Error;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// How many symlinks a chain may pass through before it counts as a cycle;
+/// Linux gives up at the same depth.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Merge groups that share a path when they agree on its hash, and drop the
+/// repeats inside each group. Groups connected through a shared path whose
+/// hashes disagree are all left out and reported as one conflict.
+pub(crate) fn merge_overlapping_groups(
+    groups: Vec<Vec<CzkawkaDuplicateJsonFormatElement>>,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> Vec<Vec<CzkawkaDuplicateJsonFormatElement>> {
+    let keys = groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .map(|entry| entry_key(Path::new(&entry.path)))
+                .collect::<Vec<PathBuf>>()
+        })
+        .collect::<Vec<Vec<PathBuf>>>();
+
+    let components = connected_groups(&keys);
+    let mut groups = groups.into_iter().map(Some).collect::<Vec<_>>();
+    let mut planned = Vec::new();
+
+    for indices in components {
+        let component = indices
+            .iter()
+            .map(|&index| {
+                let group = groups[index]
+                    .take()
+                    .expect("each group is in one component");
+                (group, &keys[index])
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(conflict) = hash_conflict(&component) {
+            eprintln!("{}", conflict);
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(anyhow::anyhow!(conflict));
+            continue;
+        }
+
+        if component.len() > 1 {
+            println!(
+                "Merged {} duplicate groups that share paths: {:?}",
+                component.len(),
+                component
+                    .iter()
+                    .map(|(group, _)| group.first().map(|e| e.path.as_str()).unwrap_or(""))
+                    .collect::<Vec<&str>>()
+            );
+        }
+
+        let mut first_entry_of: HashMap<&PathBuf, String> = HashMap::new();
+        let mut merged = Vec::new();
+
+        for (group, group_keys) in component {
+            for (entry, key) in group.into_iter().zip(group_keys) {
+                match first_entry_of.get(key) {
+                    Some(first) if *first == entry.path => {
+                        println!("Dropped repeated entry '{}'", entry.path);
+                    }
+                    Some(first) => {
+                        println!(
+                            "Dropped '{}': it names the same path as '{}'",
+                            entry.path, first
+                        );
+                    }
+                    None => {
+                        first_entry_of.insert(key, entry.path.clone());
+                        merged.push(entry);
+                    }
+                }
+            }
+        }
+
+        planned.push(merged);
+    }
+
+    planned
+}
+
+/// The conflict report for groups that list one path under different hashes,
+/// if any.
+fn hash_conflict(
+    component: &[(Vec<CzkawkaDuplicateJsonFormatElement>, &Vec<PathBuf>)],
+) -> Option<String> {
+    let mut hash_of: HashMap<&PathBuf, &str> = HashMap::new();
+    let mut clashes = Vec::new();
+
+    for (group, group_keys) in component {
+        for (entry, key) in group.iter().zip(group_keys.iter()) {
+            match hash_of.get(key) {
+                Some(&hash) if hash != entry.hash => {
+                    clashes.push(format!("'{}' ({} vs {})", entry.path, hash, entry.hash));
+                }
+                Some(_) => {}
+                None => {
+                    hash_of.insert(key, &entry.hash);
+                }
+            }
+        }
+    }
+
+    if clashes.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "Conflicting duplicate groups list the same path with different hashes ({}); none of these {} group(s) were touched: {:?}",
+        clashes.join(", "),
+        component.len(),
+        component
+            .iter()
+            .flat_map(|(group, _)| group.iter().map(|e| e.path.as_str()))
+            .collect::<Vec<&str>>()
+    ))
+}
+
+/// The path a report entry names, with its directory canonicalized so the
+/// same entry spelled two ways compares equal. The entry itself is not
+/// resolved: a symlink and its target stay two entries.
+pub(crate) fn entry_key(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+
+    std::fs::canonicalize(parent)
+        .map(|parent| parent.join(name))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Where a report entry leads once its own symlinks are followed.
+enum Resolution {
+    /// The entry is a file itself, not a symlink.
+    Real(SameFileKey),
+    /// The entry is a symlink (or chain of them) ending at the existing file
+    /// at the given path.
+    Link(SameFileKey, PathBuf),
+    /// Following the entry's symlinks comes back to one already visited.
+    Cycle(Vec<PathBuf>),
+    /// The entry or the end of its chain cannot be reached; the per-group
+    /// checks report it.
+    Missing,
+}
+
+impl Resolution {
+    fn key(&self) -> Option<&SameFileKey> {
+        match self {
+            Resolution::Real(key) | Resolution::Link(key, _) => Some(key),
+            Resolution::Cycle(_) | Resolution::Missing => None,
+        }
+    }
+}
+
+/// Make sure every link the run creates points straight at a real file.
+///
+/// Each entry is resolved through its symlinks to the file it reaches, and
+/// groups that reach the same file (say an original in one report and a
+/// symlink to it in another) become one group, so the file is linked to
+/// rather than turned into a link under another group's links. Groups whose
+/// members all link to one file are already deduplicated and dropped; symlink
+/// cycles and groups that reach one file but disagree on its hash are
+/// reported and left alone. Symlinks to files outside their group are left to
+/// `--existing-symlinks`.
+pub(crate) fn plan_links(
+    groups: Vec<Vec<CzkawkaDuplicateJsonFormatElement>>,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> Vec<Vec<CzkawkaDuplicateJsonFormatElement>> {
+    let resolutions = groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .map(|entry| resolve(Path::new(&entry.path)))
+                .collect::<Vec<Resolution>>()
+        })
+        .collect::<Vec<Vec<Resolution>>>();
+    let keys = resolutions
+        .iter()
+        .map(|group| group.iter().filter_map(Resolution::key).collect::<Vec<_>>())
+        .collect::<Vec<Vec<&SameFileKey>>>();
+
+    let report = |message: String| {
+        eprintln!("{}", message);
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(message));
+    };
+
+    let components = connected_groups(&keys);
+    let mut groups = groups.into_iter().map(Some).collect::<Vec<_>>();
+    let mut planned = Vec::new();
+
+    for indices in components {
+        let component = indices
+            .iter()
+            .map(|&index| {
+                let group = groups[index]
+                    .take()
+                    .expect("each group is in one component");
+                (group, &resolutions[index])
+            })
+            .collect::<Vec<_>>();
+        let paths = component
+            .iter()
+            .flat_map(|(group, _)| group.iter().map(|e| e.path.as_str()))
+            .collect::<Vec<&str>>();
+
+        let cycles = component
+            .iter()
+            .flat_map(|(_, resolved)| resolved.iter())
+            .filter_map(|resolution| match resolution {
+                Resolution::Cycle(chain) => Some(
+                    chain
+                        .iter()
+                        .map(|path| format!("'{}'", path.display()))
+                        .collect::<Vec<String>>()
+                        .join(" -> "),
+                ),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        if !cycles.is_empty() {
+            report(format!(
+                "Symlink cycle {}; its group was left alone: {:?}",
+                cycles.join(", "),
+                paths
+            ));
+            continue;
+        }
+
+        let mut hashes = component
+            .iter()
+            .flat_map(|(group, _)| group.iter().map(|e| e.hash.as_str()))
+            .collect::<Vec<&str>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        if component.len() > 1 && hashes.len() > 1 {
+            report(format!(
+                "Duplicate groups reach the same file but disagree on its hash ({}); none of these {} group(s) were touched: {:?}",
+                hashes.join(" vs "),
+                component.len(),
+                paths
+            ));
+            continue;
+        }
+
+        let has_real_file = component
+            .iter()
+            .flat_map(|(_, resolved)| resolved.iter())
+            .any(|resolution| matches!(resolution, Resolution::Real(_)));
+        let links = component
+            .iter()
+            .flat_map(|(_, resolved)| resolved.iter())
+            .filter_map(|resolution| match resolution {
+                Resolution::Link(key, target) => Some((key, target)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // An earlier vault or canonical-dir run leaves every member linking to
+        // one file outside the report.
+        if let Some((key, target)) = links.first()
+            && !has_real_file
+            && links.iter().all(|(other, _)| other == key)
+        {
+            println!(
+                "Already deduplicated: every member of the group links to '{}': {:?}",
+                target.display(),
+                paths
+            );
+            continue;
+        }
+
+        if component.len() > 1 {
+            println!(
+                "Merged {} duplicate groups whose entries reach the same file: {:?}",
+                component.len(),
+                paths
+            );
+        }
+
+        planned.push(
+            component
+                .into_iter()
+                .flat_map(|(group, _)| group)
+                .collect::<Vec<CzkawkaDuplicateJsonFormatElement>>(),
+        );
+    }
+
+    planned
+}
+
+/// Follow `path` through its symlinks, one hop at a time so a cycle can be
+/// named instead of surfacing as a generic lookup error.
+fn resolve(path: &Path) -> Resolution {
+    let mut chain = vec![entry_key(path)];
+    let mut current = path.to_path_buf();
+
+    loop {
+        let Ok(metadata) = std::fs::symlink_metadata(&current) else {
+            return Resolution::Missing;
+        };
+
+        if !metadata.file_type().is_symlink() {
+            return match SameFileKey::of_target(&current) {
+                Ok(key) if chain.len() == 1 => Resolution::Real(key),
+                Ok(key) => Resolution::Link(key, current),
+                Err(_) => Resolution::Missing,
+            };
+        }
+
+        let Ok(target) = std::fs::read_link(&current) else {
+            return Resolution::Missing;
+        };
+        let next = match current.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+        let key = entry_key(&next);
+        let seen = chain.contains(&key);
+        chain.push(key);
+
+        if seen || chain.len() > MAX_LINK_HOPS {
+            return Resolution::Cycle(chain);
+        }
+
+        current = next;
+    }
+}
+
+/// Group indices connected through a shared key, each component in the order
+/// of its first group.
+fn connected_groups<K: Eq + std::hash::Hash>(keys: &[Vec<K>]) -> Vec<Vec<usize>> {
+    let mut components = Components::new(keys.len());
+    let mut first_group_of = HashMap::new();
+
+    for (index, group_keys) in keys.iter().enumerate() {
+        for key in group_keys {
+            match first_group_of.get(key) {
+                Some(&first) => components.join(first, index),
+                None => {
+                    first_group_of.insert(key, index);
+                }
+            }
+        }
+    }
+
+    let mut members_of: Vec<Vec<usize>> = vec![Vec::new(); keys.len()];
+    for index in 0..keys.len() {
+        members_of[components.find(index)].push(index);
+    }
+
+    members_of
+        .into_iter()
+        .filter(|indices| !indices.is_empty())
+        .collect()
+}
+
+/// Union-find over group indices.
+struct Components {
+    parent: Vec<usize>,
+}
+
+impl Components {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, index: usize) -> usize {
+        let mut root = index;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+
+        let mut current = index;
+        while self.parent[current] != root {
+            let next = self.parent[current];
+            self.parent[current] = root;
+            current = next;
+        }
+
+        root
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[b.max(a)] = a.min(b);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! Find backups that interrupted or failed runs left behind under an
+//! allow-root, work out which path each one came from, and restore, delete, or
+//! list it.
+
+use crate::args::{self, RecoverAction};
+use crate::cancel::CancellationToken;
+use crate::staging::{self, BackupOrigin};
+use crate::{journal, lock, rename, symlinks};
+use anyhow::{Context, Error, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// What became of one backup.
+enum Outcome {
+    Restored,
+    Deleted,
+    Left,
+}
+
+/// What the path a backup came from holds now.
+enum OriginalState {
+    Missing,
+    Link(PathBuf),
+    Real { is_dir: bool },
+}
+
+impl std::fmt::Display for OriginalState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginalState::Missing => write!(f, "missing"),
+            OriginalState::Link(target) => write!(f, "a symlink to '{}'", target.display()),
+            OriginalState::Real { is_dir: true } => write!(f, "a real directory"),
+            OriginalState::Real { is_dir: false } => write!(f, "a regular file"),
+        }
+    }
+}
+
+/// Walk every `args.roots` entry for backups named by any staging strategy
+/// (`*.czkawka-bak[-N]`, `.<name>.czkawka-bak.<run-id>`, or entries of a
+/// `.czkawka-staging` directory) and apply `args.action` to each.
+///
+/// A backup is only restored over a path that is missing or holds a link, and
+/// only deleted while its original path still resolves to the same bytes (the
+/// same tree, for a directory), so neither action can destroy the last copy of
+/// its content. `suffix`
+/// backups lose their extension; the journals in `state_dir` and the files
+/// next to the backup are used to find the full name, and ambiguous backups
+/// are reported and left alone.
+///
+/// # Errors
+/// - another run holds a lock on an overlapping root
+/// - a root cannot be walked
+/// - a backup's original path cannot be determined, or the action is refused
+///   for the current state of that path
+/// - a rename or removal fails
+///
+/// # Examples
+/// ```no_run
+/// use czkawka_dupe_to_symlinks::{RecoverAction, RecoverArgs, recover_backups};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let args = RecoverArgs {
+///     roots: vec!["/srv/media".into()],
+///     action: RecoverAction::Restore,
+///     dry_run: true,
+///     ..Default::default()
+/// };
+///
+/// recover_backups(&args)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn recover_backups(args: &args::RecoverArgs) -> Result<(), Error> {
+    if args.roots.is_empty() {
+        anyhow::bail!("No --root paths configured.");
+    }
+
+    let staged_names = match &args.state_dir {
+        Some(state_dir) => canonical_names(journal::staged_names(state_dir)?),
+        None => HashMap::new(),
+    };
+
+    let lock_dir = lock::lock_dir(args.lock_dir.as_deref(), args.state_dir.as_deref());
+    let _run_lock = match &lock_dir {
+        Some(dir) if !args.dry_run && args.action != RecoverAction::Leave => Some(lock::acquire(
+            dir,
+            &args.roots,
+            &format!("recover-{}", std::process::id()),
+            false,
+            &CancellationToken::new(),
+        )?),
+        _ => None,
+    };
+
+    let mut errors = Vec::new();
+    let (mut found, mut restored, mut deleted, mut left) = (0, 0, 0, 0);
+
+    for root in &args.roots {
+        let backups = match find_backups(root) {
+            Ok(backups) => backups,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                errors.push(e);
+                continue;
+            }
+        };
+
+        for backup in backups {
+            found += 1;
+
+            match recover_backup(args, root, &backup, &staged_names) {
+                Ok((outcome, message)) => {
+                    println!("{}", message);
+                    match outcome {
+                        Outcome::Restored => restored += 1,
+                        Outcome::Deleted => deleted += 1,
+                        Outcome::Left => left += 1,
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    errors.push(e);
+                }
+            }
+        }
+    }
+
+    println!(
+        "{}Found {} backup(s): {} restored, {} deleted, {} left in place",
+        if args.dry_run { "[Dry Run] " } else { "" },
+        found,
+        restored,
+        deleted,
+        left
+    );
+
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Encountered {} error(s):\n{}",
+            errors.len(),
+            errors
+                .iter()
+                .map(|e| format!("  - {:#}", e))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Every backup under `root`, in a stable order. Backup directories are
+/// reported as a whole and not descended into.
+fn find_backups(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut backups = Vec::new();
+    let mut walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter();
+
+    while let Some(entry) = walker.next() {
+        let entry = entry.with_context(|| format!("Failed to walk '{}'.", root.display()))?;
+
+        if staging::backup_origin(entry.path()).is_some() {
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            backups.push(entry.into_path());
+        }
+    }
+
+    Ok(backups)
+}
+
+fn recover_backup(
+    args: &args::RecoverArgs,
+    root: &Path,
+    backup: &Path,
+    staged_names: &HashMap<PathBuf, PathBuf>,
+) -> Result<(Outcome, String), Error> {
+    let (original, guessed) = match original_path(backup, staged_names) {
+        Ok(found) => found,
+        // Listing never fails just because a backup cannot be placed.
+        Err(e) if args.action == RecoverAction::Leave => {
+            return Ok((Outcome::Left, format!("{:#}", e)));
+        }
+        Err(e) => return Err(e),
+    };
+    let parent = original.parent().unwrap_or(Path::new(""));
+
+    if !std::fs::canonicalize(parent).is_ok_and(|parent| parent.starts_with(root)) {
+        anyhow::bail!(
+            "Left '{}': its original path '{}' is outside '{}'.",
+            backup.display(),
+            original.display(),
+            root.display()
+        );
+    }
+
+    let state = original_state(&original)?;
+    let dry_run = if args.dry_run { "[Dry Run] Would " } else { "" };
+
+    match args.action {
+        RecoverAction::Leave => Ok((
+            Outcome::Left,
+            format!(
+                "'{}' belongs to '{}', which is {}",
+                backup.display(),
+                original.display(),
+                state
+            ),
+        )),
+        RecoverAction::Restore => {
+            if let OriginalState::Real { .. } = state {
+                anyhow::bail!(
+                    "Left '{}': '{}' is {}; only a missing path or a link is restored.",
+                    backup.display(),
+                    original.display(),
+                    state
+                );
+            }
+
+            // A name guessed from the stem may be another file's (`photo.png`
+            // for a `photo.jpg` backup); it is only restored over a link that
+            // already leads to the backup's bytes.
+            if guessed && !link_leads_to_same_file(backup, &original, &state) {
+                anyhow::bail!(
+                    "Left '{}': no journal names it and its original name '{}' is only guessed from the stem, so it is restored only over a link to the same content.",
+                    backup.display(),
+                    original.display()
+                );
+            }
+
+            if !args.dry_run {
+                restore_backup(backup, &original, &state)?;
+            }
+
+            Ok((
+                Outcome::Restored,
+                format!(
+                    "{}{} '{}' to '{}' ({})",
+                    dry_run,
+                    if args.dry_run { "restore" } else { "Restored" },
+                    backup.display(),
+                    original.display(),
+                    state
+                ),
+            ))
+        }
+        RecoverAction::Delete => {
+            ensure_content_remains(backup, &original, &state)?;
+
+            if !args.dry_run {
+                remove_backup(backup)?;
+            }
+
+            Ok((
+                Outcome::Deleted,
+                format!(
+                    "{}{} '{}' ('{}' is {})",
+                    dry_run,
+                    if args.dry_run { "delete" } else { "Deleted" },
+                    backup.display(),
+                    original.display(),
+                    state
+                ),
+            ))
+        }
+    }
+}
+
+/// Journal entries are keyed by the paths the run used; make them comparable
+/// with the canonical paths the walk produces.
+fn canonical_names(names: HashMap<PathBuf, PathBuf>) -> HashMap<PathBuf, PathBuf> {
+    let canonical = |path: &Path| -> Option<PathBuf> {
+        let parent = std::fs::canonicalize(path.parent()?).ok()?;
+        Some(parent.join(path.file_name()?))
+    };
+
+    names
+        .iter()
+        .filter_map(|(staged, duplicate)| Some((canonical(staged)?, canonical(duplicate)?)))
+        .collect()
+}
+
+/// The path `backup` was taken from, and whether it is only guessed from a
+/// sibling sharing its stem (neither a journal nor the backup's name says).
+fn original_path(
+    backup: &Path,
+    staged_names: &HashMap<PathBuf, PathBuf>,
+) -> Result<(PathBuf, bool), Error> {
+    if let Some(duplicate) = staged_names.get(backup) {
+        return Ok((duplicate.clone(), false));
+    }
+
+    let (parent, stem) = match staging::backup_origin(backup) {
+        Some(BackupOrigin::Exact(original)) => return Ok((original, false)),
+        Some(BackupOrigin::Stem { parent, stem }) => (parent, stem),
+        None => anyhow::bail!("'{}' is not a backup name.", backup.display()),
+    };
+
+    let mut candidates = Vec::new();
+    for entry in std::fs::read_dir(&parent)
+        .with_context(|| format!("Failed to read directory '{}'.", parent.display()))?
+    {
+        let path = entry
+            .with_context(|| format!("Failed to read directory '{}'.", parent.display()))?
+            .path();
+
+        if staging::backup_origin(&path).is_none()
+            && path.file_stem().is_some_and(|name| name == stem)
+        {
+            candidates.push(path);
+        }
+    }
+    candidates.sort();
+
+    match candidates.len() {
+        1 => Ok((candidates.remove(0), true)),
+        0 => anyhow::bail!(
+            "Left '{}': no '{}' or '{}.*' is left next to it and no journal names it, so its original name is unknown.",
+            backup.display(),
+            stem.to_string_lossy(),
+            stem.to_string_lossy()
+        ),
+        _ => anyhow::bail!(
+            "Left '{}': it could belong to any of {}.",
+            backup.display(),
+            candidates
+                .iter()
+                .map(|path| format!("'{}'", path.display()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn original_state(original: &Path) -> Result<OriginalState, Error> {
+    match std::fs::symlink_metadata(original) {
+        Ok(metadata) if metadata.is_symlink() => Ok(OriginalState::Link(
+            std::fs::read_link(original)
+                .with_context(|| format!("Failed to read link '{}'.", original.display()))?,
+        )),
+        Ok(metadata) => Ok(OriginalState::Real {
+            is_dir: metadata.is_dir(),
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(OriginalState::Missing),
+        Err(e) => Err(e).with_context(|| format!("Failed to inspect '{}'.", original.display())),
+    }
+}
+
+/// Whether `original` is a link resolving to a file with the same bytes as
+/// the file `backup`.
+fn link_leads_to_same_file(backup: &Path, original: &Path, state: &OriginalState) -> bool {
+    let (Ok(backup_metadata), Ok(original_metadata)) = (
+        std::fs::symlink_metadata(backup),
+        std::fs::metadata(original),
+    ) else {
+        return false;
+    };
+
+    matches!(state, OriginalState::Link(_))
+        && backup_metadata.is_file()
+        && original_metadata.is_file()
+        && backup_metadata.len() == original_metadata.len()
+        && symlinks::same_content(backup, original).unwrap_or(false)
+}
+
+/// Put `backup` back at `original`, which is missing or a link.
+fn restore_backup(backup: &Path, original: &Path, state: &OriginalState) -> Result<(), Error> {
+    let context = || {
+        format!(
+            "Failed to restore '{}' to '{}'.",
+            backup.display(),
+            original.display()
+        )
+    };
+
+    if let OriginalState::Missing = state {
+        return rename::rename_noreplace(backup, original).with_context(context);
+    }
+
+    // Swap the backup with the link so the path never goes missing, then drop
+    // the link that now sits under the backup's name.
+    match rename::exchange(backup, original) {
+        Ok(()) => std::fs::remove_file(backup).with_context(|| {
+            format!(
+                "Restored '{}' but failed to remove the old link now at '{}'.",
+                original.display(),
+                backup.display()
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            if std::fs::symlink_metadata(backup).is_ok_and(|metadata| metadata.is_dir()) {
+                // A directory cannot be renamed over a symlink.
+                std::fs::remove_file(original).with_context(context)?;
+                rename::rename_noreplace(backup, original).with_context(context)
+            } else {
+                std::fs::rename(backup, original).with_context(context)
+            }
+        }
+        Err(e) => Err(e).with_context(context),
+    }
+}
+
+/// Refuse to delete a backup that is real data unless `original` still
+/// resolves to the same content: a file must match byte for byte, a directory
+/// must have the same [`journal::tree_digest`]. Anything else is refused.
+fn ensure_content_remains(
+    backup: &Path,
+    original: &Path,
+    state: &OriginalState,
+) -> Result<(), Error> {
+    let backup_metadata = std::fs::symlink_metadata(backup)
+        .with_context(|| format!("Failed to inspect '{}'.", backup.display()))?;
+
+    // A link left under a staging name (a run stopped before swapping it in)
+    // holds no content of its own.
+    if backup_metadata.is_symlink() {
+        return Ok(());
+    }
+
+    let Ok(original_metadata) = std::fs::metadata(original) else {
+        anyhow::bail!(
+            "Left '{}': '{}' is {} and does not resolve, so the backup may be the only copy.",
+            backup.display(),
+            original.display(),
+            state
+        );
+    };
+
+    let compare = || -> Result<bool, std::io::Error> {
+        if backup_metadata.is_dir() && original_metadata.is_dir() {
+            Ok(journal::tree_digest(backup)? == journal::tree_digest(original)?)
+        } else if backup_metadata.is_file() && original_metadata.is_file() {
+            Ok(backup_metadata.len() == original_metadata.len()
+                && symlinks::same_content(backup, original)?)
+        } else {
+            Ok(false)
+        }
+    };
+
+    let same = compare().with_context(|| {
+        format!(
+            "Left '{}': failed to compare it with '{}'.",
+            backup.display(),
+            original.display()
+        )
+    })?;
+
+    if !same {
+        anyhow::bail!(
+            "Left '{}': '{}' no longer resolves to the same content, so the backup may be the only copy.",
+            backup.display(),
+            original.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn remove_backup(backup: &Path) -> Result<(), Error> {
+    let is_dir = std::fs::symlink_metadata(backup).is_ok_and(|metadata| metadata.is_dir());
+    let removed = if is_dir {
+        std::fs::remove_dir_all(backup)
+    } else {
+        std::fs::remove_file(backup)
+    };
+
+    removed.with_context(|| format!("Failed to delete '{}'.", backup.display()))
+}
@@ -0,0 +1,239 @@
+//! Atomic rename primitives used to swap links into place. Each one either
+//! completes in a single step or leaves both paths untouched, so readers never
+//! observe a missing path. `--durable` runs also fsync the directories these
+//! renames touch, so the result survives a power loss.
+
+use std::path::Path;
+
+/// Atomically swap the directory entries `a` and `b`; both must exist.
+///
+/// Fails with [`std::io::ErrorKind::Unsupported`] when the platform or the
+/// filesystem cannot exchange entries, so callers can fall back.
+pub(crate) fn exchange(a: &Path, b: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let a = std::ffi::CString::new(a.as_os_str().as_bytes())?;
+        let b = std::ffi::CString::new(b.as_os_str().as_bytes())?;
+
+        // SAFETY: both pointers come from NUL-terminated `CString`s that outlive
+        // the call. The raw syscall is used because older glibc and musl do
+        // not wrap renameat2.
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                a.as_ptr(),
+                libc::AT_FDCWD,
+                b.as_ptr(),
+                libc::RENAME_EXCHANGE,
+            )
+        };
+
+        if rc != 0 {
+            return Err(unsupported_if_unknown(std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_vendor = "apple")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let a = std::ffi::CString::new(a.as_os_str().as_bytes())?;
+        let b = std::ffi::CString::new(b.as_os_str().as_bytes())?;
+
+        // SAFETY: both pointers come from NUL-terminated `CString`s that outlive
+        // the call.
+        let rc = unsafe { libc::renamex_np(a.as_ptr(), b.as_ptr(), libc::RENAME_SWAP) };
+
+        if rc != 0 {
+            return Err(unsupported_if_unknown(std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+    {
+        let _ = (a, b);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "atomic exchange is not supported on this platform",
+        ))
+    }
+}
+
+/// Rename `from` to `to`, failing with `AlreadyExists` instead of replacing an
+/// existing `to`, so concurrent workers can never claim the same name.
+pub(crate) fn rename_noreplace(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let from_c = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+        let to_c = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+
+        // SAFETY: both pointers come from NUL-terminated `CString`s that outlive
+        // the call.
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                from_c.as_ptr(),
+                libc::AT_FDCWD,
+                to_c.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        };
+
+        if rc == 0 {
+            return Ok(());
+        }
+
+        let err = unsupported_if_unknown(std::io::Error::last_os_error());
+        if err.kind() != std::io::ErrorKind::Unsupported {
+            return Err(err);
+        }
+    }
+
+    #[cfg(target_vendor = "apple")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let from_c = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+        let to_c = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+
+        // SAFETY: both pointers come from NUL-terminated `CString`s that outlive
+        // the call.
+        let rc = unsafe { libc::renamex_np(from_c.as_ptr(), to_c.as_ptr(), libc::RENAME_EXCL) };
+
+        if rc == 0 {
+            return Ok(());
+        }
+
+        let err = unsupported_if_unknown(std::io::Error::last_os_error());
+        if err.kind() != std::io::ErrorKind::Unsupported {
+            return Err(err);
+        }
+    }
+
+    rename_noreplace_fallback(from, to)
+}
+
+/// Emulate a no-replace rename with operations that create exclusively: a
+/// hard link claims the new name of a file, and an empty placeholder directory
+/// claims it for a directory before the rename lands on that placeholder.
+fn rename_noreplace_fallback(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if std::fs::symlink_metadata(from)?.is_dir() {
+        // Windows never lets a rename replace a directory, so a plain rename
+        // already refuses to clobber one.
+        #[cfg(target_family = "windows")]
+        {
+            if std::fs::symlink_metadata(to).is_ok() {
+                return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists));
+            }
+            return std::fs::rename(from, to);
+        }
+
+        #[cfg(target_family = "unix")]
+        {
+            std::fs::create_dir(to)?;
+            if let Err(e) = std::fs::rename(from, to) {
+                let _ = std::fs::remove_dir(to);
+                return Err(e);
+            }
+            return Ok(());
+        }
+    }
+
+    std::fs::hard_link(from, to)?;
+    if let Err(e) = std::fs::remove_file(from) {
+        let _ = std::fs::remove_file(to);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Fsync the directories that hold `paths`, once each, so entries created,
+/// renamed or removed in them are on disk.
+pub(crate) fn sync_parents(paths: &[&Path]) -> Result<(), std::io::Error> {
+    let mut synced: Vec<&Path> = Vec::new();
+
+    for path in paths {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        if !synced.contains(&parent) {
+            sync_dir(parent)?;
+            synced.push(parent);
+        }
+    }
+
+    Ok(())
+}
+
+/// Fsync every directory from `path`'s parent up to and including `base`, for
+/// paths whose missing parents were just created under `base`.
+pub(crate) fn sync_parents_up_to(path: &Path, base: &Path) -> Result<(), std::io::Error> {
+    let mut dir = path.parent();
+
+    while let Some(current) = dir {
+        if !current.starts_with(base) {
+            break;
+        }
+        sync_dir(current)?;
+        dir = current.parent();
+    }
+
+    Ok(())
+}
+
+/// Fsync a file's content and metadata.
+pub(crate) fn sync_file(path: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        std::fs::File::open(path)?.sync_all()
+    }
+
+    // Windows only flushes handles opened for writing.
+    #[cfg(not(target_family = "unix"))]
+    {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .sync_all()
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        std::fs::File::open(dir)?.sync_all()
+    }
+
+    // Windows cannot open a directory for flushing without backup semantics;
+    // NTFS journals metadata changes itself.
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
+/// Old kernels answer ENOSYS and filesystems without support answer EINVAL or
+/// ENOTSUP; all of them mean "use the fallback", not "the paths are wrong".
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+fn unsupported_if_unknown(err: std::io::Error) -> std::io::Error {
+    match err.raw_os_error() {
+        Some(libc::ENOSYS | libc::EINVAL | libc::ENOTSUP) => {
+            std::io::Error::new(std::io::ErrorKind::Unsupported, err)
+        }
+        _ => err,
+    }
+}
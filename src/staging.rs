@@ -0,0 +1,200 @@
+//! Where a run parks replaced files until their links are in place. Every name
+//! is claimed with an exclusive create or a no-replace rename, so parallel
+//! workers and leftovers from earlier runs never collide.
+
+use crate::args;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Name of the hidden directory used by [`args::Staging::Dir`].
+const STAGING_DIR_NAME: &str = ".czkawka-staging";
+
+/// The staging strategy of one run, plus the staging directories it created so
+/// they can be removed again once empty.
+pub(crate) struct StagingArea {
+    strategy: args::Staging,
+    run_id: String,
+    created_dirs: std::sync::Mutex<BTreeSet<PathBuf>>,
+}
+
+impl StagingArea {
+    pub(crate) fn new(strategy: args::Staging, run_id: String) -> Self {
+        Self {
+            strategy,
+            run_id,
+            created_dirs: std::sync::Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub(crate) fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// The first backup name for `path`, counting from `*counter`, that is free
+    /// right now; `*counter` is left at that name. Callers that journal a name
+    /// before creating it use this, and bump `*counter` past a name another
+    /// worker takes in between.
+    pub(crate) fn free_name(
+        &self,
+        path: &Path,
+        counter: &mut u32,
+    ) -> Result<PathBuf, std::io::Error> {
+        loop {
+            let candidate = self.candidate(path, *counter)?;
+
+            match std::fs::symlink_metadata(&candidate) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(candidate),
+                Err(e) => return Err(e),
+                Ok(_) => *counter += 1,
+            }
+        }
+    }
+
+    /// Where this run's backups live, for the run summary.
+    pub(crate) fn describe(&self) -> String {
+        match self.strategy {
+            args::Staging::Suffix => {
+                "next to each duplicate as '<stem>.czkawka-bak[-N]'".to_string()
+            }
+            args::Staging::Hidden => format!(
+                "next to each duplicate as '.<name>.czkawka-bak.{}[-N]'",
+                self.run_id
+            ),
+            args::Staging::Dir => format!(
+                "in '{}/<name>.{}[-N]' inside each duplicate's directory",
+                STAGING_DIR_NAME, self.run_id
+            ),
+        }
+    }
+
+    /// Remove the staging directories this run created, leaving any that still
+    /// hold backups (or files from other runs) in place.
+    pub(crate) fn remove_empty_dirs(&self) {
+        let dirs = self
+            .created_dirs
+            .lock()
+            .expect("Should be able to unwrap lock");
+
+        for dir in dirs.iter() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+
+    fn candidate(&self, path: &Path, counter: u32) -> Result<PathBuf, std::io::Error> {
+        if self.strategy == args::Staging::Suffix {
+            let suffix = if counter == 0 {
+                "czkawka-bak".to_string()
+            } else {
+                format!("czkawka-bak-{}", counter)
+            };
+
+            return Ok(path.with_extension(suffix));
+        }
+
+        let file_name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("'{}' has no file name to stage", path.display()),
+            )
+        })?;
+        let mut name = file_name.to_os_string();
+        let parent = path.parent().unwrap_or(Path::new(""));
+
+        let directory = match self.strategy {
+            args::Staging::Hidden => {
+                name = OsString::from(".");
+                name.push(file_name);
+                name.push(".czkawka-bak.");
+                name.push(&self.run_id);
+                parent.to_path_buf()
+            }
+            _ => {
+                name.push(".");
+                name.push(&self.run_id);
+                self.ensure_staging_dir(parent)?
+            }
+        };
+
+        if counter > 0 {
+            name.push(format!("-{}", counter));
+        }
+
+        Ok(directory.join(name))
+    }
+
+    fn ensure_staging_dir(&self, parent: &Path) -> Result<PathBuf, std::io::Error> {
+        let dir = parent.join(STAGING_DIR_NAME);
+
+        match std::fs::create_dir(&dir) {
+            Ok(()) => {
+                self.created_dirs
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .insert(dir.clone());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(dir)
+    }
+}
+
+/// Where a backup found on disk came from, worked out from its name alone.
+pub(crate) enum BackupOrigin {
+    /// `hidden` and `dir` backups keep the full name of the replaced entry.
+    Exact(PathBuf),
+    /// `suffix` backups replaced the extension, so any sibling with this stem
+    /// (or the bare stem) may be the replaced entry.
+    Stem { parent: PathBuf, stem: OsString },
+}
+
+/// Recognise a backup name produced by any [`args::Staging`] strategy.
+pub(crate) fn backup_origin(path: &Path) -> Option<BackupOrigin> {
+    let name = path.file_name()?.to_str()?;
+    let parent = path.parent().unwrap_or(Path::new(""));
+
+    if parent
+        .file_name()
+        .is_some_and(|dir| dir == STAGING_DIR_NAME)
+    {
+        let (original, run_id) = name.rsplit_once('.')?;
+        if original.is_empty() || !is_run_id(run_id) {
+            return None;
+        }
+        let directory = parent.parent().unwrap_or(Path::new(""));
+        return Some(BackupOrigin::Exact(directory.join(original)));
+    }
+
+    if let Some(hidden) = name.strip_prefix('.')
+        && let Some((original, run_id)) = hidden.rsplit_once(".czkawka-bak.")
+        && !original.is_empty()
+        && is_run_id(run_id)
+    {
+        return Some(BackupOrigin::Exact(parent.join(original)));
+    }
+
+    let (stem, extension) = name.rsplit_once('.')?;
+    let counter = extension.strip_prefix("czkawka-bak")?;
+    let is_suffix = counter.is_empty()
+        || counter
+            .strip_prefix('-')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+
+    if stem.is_empty() || !is_suffix {
+        return None;
+    }
+
+    Some(BackupOrigin::Stem {
+        parent: parent.to_path_buf(),
+        stem: OsString::from(stem),
+    })
+}
+
+/// Run ids are `<secs>-<pid>[-<seq>]`, optionally followed by a `-N` counter.
+fn is_run_id(value: &str) -> bool {
+    value.starts_with(|c: char| c.is_ascii_digit())
+        && value.contains('-')
+        && value.chars().all(|c| c.is_ascii_digit() || c == '-')
+}
@@ -2,7 +2,10 @@
 //! library consumers. The implementation focuses on determinism and
 //! rollback-friendly filesystem mutations.
 
-use crate::args;
+use crate::cancel::CancellationToken;
+use crate::journal::Journal;
+use crate::staging::StagingArea;
+use crate::{args, clone, directories, journal, lock, plan, rename};
 use anyhow::{Context, Error, Result};
 use rayon::prelude::*;
 use std::collections::HashMap;
@@ -10,18 +13,23 @@ use std::collections::HashMap;
 /// Replace every duplicate described in the previously validated JSON reports.
 ///
 /// The function re-reads the filesystem metadata to ensure hashes still match,
-/// enforces the allow-root sandbox, and stages `*.czkawka-bak` backups so that a
-/// failed symlink operation can be rolled back automatically.
+/// enforces the allow-root sandbox, and keeps each replaced file as a backup
+/// (placed according to [`args::Staging`]) until its link is in place.
+///
+/// Cancelling `cancel` stops the run between replacements: groups not yet
+/// started are left alone, in-flight replacements are finished (or their group
+/// rolled back with [`args::Args::atomic_groups`]), and a summary is printed.
 ///
 /// # Errors
 /// - the JSON payload references files outside the declared `allow_roots`
 /// - hashes or live file metadata disagree with the JSON report
-/// - the OS denies renaming or symlink creation
+/// - the OS denies renaming or link creation
+/// - the run was cancelled before every group was processed
 ///
 /// # Examples
 /// ```no_run
 /// use czkawka_dupe_to_symlinks::{
-///     replace_duplicates_with_symlinks, validate_files, Args, OriginalToKeep,
+///     replace_duplicates_with_symlinks, validate_files, Args, CancellationToken, OriginalToKeep,
 /// };
 ///
 /// # fn main() -> anyhow::Result<()> {
@@ -30,16 +38,18 @@ use std::collections::HashMap;
 ///     dry_run: false,
 ///     original_to_keep: OriginalToKeep::Newest,
 ///     allow_roots: vec!["/srv/media".into()],
+///     ..Default::default()
 /// };
 ///
 /// let files = validate_files(&args.input_file_path)?;
-/// replace_duplicates_with_symlinks(&args, &files)?;
+/// replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())?;
 /// # Ok(())
 /// # }
 /// ```
 pub fn replace_duplicates_with_symlinks(
     args: &args::Args,
     input_files: &[std::path::PathBuf],
+    cancel: &CancellationToken,
 ) -> Result<(), Error> {
     let json_by_file = input_files
         .into_par_iter()
@@ -70,100 +80,275 @@ pub fn replace_duplicates_with_symlinks(
         }
     };
 
+    let placement = match resolve_placement(args) {
+        Ok(placement) => placement,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Err(e);
+        }
+    };
+
+    let staging = StagingArea::new(args.staging, new_run_id());
+
+    // Interrupted runs are resolved under their own root locks, so this run
+    // takes its lock only once they are dealt with.
+    if let Some(state_dir) = &args.state_dir
+        && let Err(e) = journal::recover_unfinished(args, state_dir, cancel)
+    {
+        eprintln!("{:#}", e);
+        return Err(e);
+    }
+
+    let _run_lock = match lock_roots(args, staging.run_id(), cancel) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Err(e);
+        }
+    };
+
+    let journal = match start_journal(args, staging.run_id()) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Err(e);
+        }
+    };
+
+    if matches!(
+        args.link_type,
+        args::LinkType::Symlink | args::LinkType::Auto
+    ) {
+        println!(
+            "Symlink targets: {}",
+            symlink_target_name(args.symlink_target)
+        );
+    }
+
+    println!(
+        "Existing symlinks: {}",
+        existing_symlinks_name(args.existing_symlinks)
+    );
+
     // Using a thread-safe collection to store errors
     let errors: std::sync::Mutex<Vec<Error>> = std::sync::Mutex::new(vec![]);
 
-    json_by_file.into_par_iter().for_each(|dupes_in_one_json_file| {
-        dupes_in_one_json_file
-            .into_par_iter()
-            .for_each(|(_file_size, duplicate_groups)| {
-                duplicate_groups
-                    .into_par_iter()
-                    .for_each(|duplicate_group| {
-                        if duplicate_group.len() < 2 {
-                            return;
-                        }
-
-                        let hashes_match = confirm_hashes_match(&duplicate_group);
-
-                        if !hashes_match {
-                            let err = anyhow::anyhow!(
-                                "Hashes do not match for duplicate group: {:?}",
-                                duplicate_group
-                                    .iter()
-                                    .map(|e| &e.path)
-                                    .collect::<Vec<&String>>()
-                            );
-                            errors
-                                .lock()
-                                .expect("Should be able to unwrap lock")
-                                .push(err);
-
-                            return;
-                        }
-
-                        let hash = duplicate_group[0].hash.clone();
-
-                        let (files_that_exist, files_that_dont_exist): (
-                            Vec<CzkawkaDuplicateJsonFormatElement>,
-                            Vec<CzkawkaDuplicateJsonFormatElement>,
-                        ) = duplicate_group
-                            .into_par_iter()
-                            .partition(|e| std::path::Path::new(&e.path).exists());
-
-                        if !files_that_dont_exist.is_empty() {
-                            errors.lock().expect("Should be able to unwrap lock").push(
-                                anyhow::anyhow!(
-                                    "Some files specified as duplicates do not exist:\n\
-                                    {hash}\n\
-                                    {:?}\n\
-                                    The specified duplicates that do exist were replaced with symlinks.",
-                                    files_that_dont_exist
-                                        .par_iter()
-                                        .map(|e| &e.path)
-                                        .collect::<Vec<&String>>(),
-                                ),
-                            );
-                        }
-
-                        if files_that_exist.is_empty() {
-                            errors.lock().expect("Should be able to unwrap lock").push(
-                                anyhow::anyhow!(
-                                    "No files exist for duplicate group:\n\
-                                    {hash}",
-                                ),
-                            );
-                            return;
-                        }
-
-                        let mut allowed_files = Vec::new();
-                        let mut disallowed_found = false;
-
-                        for entry in files_that_exist {
-                            match ensure_path_within_roots(&entry.path, &args.allow_roots) {
-                                Ok(_) => allowed_files.push(entry),
-                                Err(e) => {
-                                    disallowed_found = true;
-                                    errors
-                                        .lock()
-                                        .expect("Should be able to unwrap lock")
-                                        .push(e);
-                                }
-                            }
-                        }
-
-                        if disallowed_found {
-                            return;
-                        }
-
-                        if allowed_files.len() < 2 {
-                            return;
-                        }
-
-                        replace_files(args, &allowed_files, &errors);
-                    });
+    // Flatten every report into one list of groups so passes that need to see
+    // all groups at once can run before anything is replaced per file.
+    let duplicate_groups = json_by_file
+        .into_iter()
+        .flat_map(|dupes_in_one_json_file| dupes_in_one_json_file.into_values())
+        .flatten()
+        .collect::<Vec<Vec<CzkawkaDuplicateJsonFormatElement>>>();
+    let duplicate_groups = plan::merge_overlapping_groups(duplicate_groups, &errors);
+    let mut duplicate_groups = plan::plan_links(duplicate_groups, &errors);
+
+    let progress = Progress {
+        cancel,
+        replaced: std::sync::atomic::AtomicUsize::new(0),
+        groups_stopped: std::sync::atomic::AtomicUsize::new(0),
+        groups_not_started: std::sync::atomic::AtomicUsize::new(0),
+        already_deduplicated: std::sync::atomic::AtomicUsize::new(0),
+        shared_bytes: std::sync::atomic::AtomicU64::new(0),
+    };
+
+    if args.dedupe_directories {
+        let replaced_dirs = directories::dedupe_directories(
+            args,
+            &staging,
+            &journal,
+            &duplicate_groups,
+            cancel,
+            &errors,
+        );
+        progress
+            .replaced
+            .fetch_add(replaced_dirs.len(), std::sync::atomic::Ordering::Relaxed);
+
+        // Members inside a replaced tree are now reached through the directory
+        // symlink and must not be touched again.
+        for duplicate_group in &mut duplicate_groups {
+            duplicate_group.retain(|e| {
+                !replaced_dirs
+                    .iter()
+                    .any(|dir| std::path::Path::new(&e.path).starts_with(dir))
             });
-    });
+        }
+    }
+
+    let group_count = duplicate_groups.len();
+
+    duplicate_groups
+        .into_par_iter()
+        .for_each(|duplicate_group| {
+            if cancel.is_cancelled() {
+                progress
+                    .groups_not_started
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                return;
+            }
+
+            if duplicate_group.len() < 2 {
+                return;
+            }
+
+            let hashes_match = confirm_hashes_match(&duplicate_group);
+
+            if !hashes_match {
+                let err = anyhow::anyhow!(
+                    "Hashes do not match for duplicate group: {:?}",
+                    duplicate_group
+                        .iter()
+                        .map(|e| &e.path)
+                        .collect::<Vec<&String>>()
+                );
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(err);
+
+                return;
+            }
+
+            let hash = duplicate_group[0].hash.clone();
+
+            let (files_that_exist, files_that_dont_exist): (
+                Vec<CzkawkaDuplicateJsonFormatElement>,
+                Vec<CzkawkaDuplicateJsonFormatElement>,
+            ) = duplicate_group
+                .into_par_iter()
+                .partition(|e| std::path::Path::new(&e.path).exists());
+
+            if !files_that_dont_exist.is_empty() {
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Some files specified as duplicates do not exist:\n\
+                        {hash}\n\
+                        {:?}\n\
+                        The specified duplicates that do exist were replaced with symlinks.",
+                        files_that_dont_exist
+                            .par_iter()
+                            .map(|e| &e.path)
+                            .collect::<Vec<&String>>(),
+                    ));
+            }
+
+            if files_that_exist.is_empty() {
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "No files exist for duplicate group:\n\
+                        {hash}",
+                    ));
+                return;
+            }
+
+            let mut allowed_files = Vec::new();
+            let mut disallowed_found = false;
+
+            for mut entry in files_that_exist {
+                match ensure_path_within_roots(&entry.path, &args.allow_roots) {
+                    Ok(canonical_path) => {
+                        entry.canonical_path = Some(canonical_path);
+                        allowed_files.push(entry);
+                    }
+                    Err(e) => {
+                        disallowed_found = true;
+                        errors
+                            .lock()
+                            .expect("Should be able to unwrap lock")
+                            .push(e);
+                    }
+                }
+            }
+
+            if disallowed_found {
+                return;
+            }
+
+            let Some(allowed_files) =
+                apply_existing_symlinks(args, &placement, allowed_files, &errors)
+            else {
+                return;
+            };
+
+            if allowed_files.len() < 2 {
+                return;
+            }
+
+            replace_files(
+                args,
+                &placement,
+                &staging,
+                &journal,
+                &progress,
+                &allowed_files,
+                &errors,
+            );
+        });
+
+    let already_deduplicated = progress
+        .already_deduplicated
+        .load(std::sync::atomic::Ordering::Relaxed);
+    if already_deduplicated > 0 {
+        println!(
+            "Already deduplicated: {} duplicate(s) left as they were",
+            already_deduplicated
+        );
+    }
+
+    if !args.dry_run && args.link_type == args::LinkType::Dedupe {
+        println!(
+            "Extent dedupe: {} duplicate(s) fully deduplicated, {} byte(s) shared",
+            progress.replaced.load(std::sync::atomic::Ordering::Relaxed),
+            progress
+                .shared_bytes
+                .load(std::sync::atomic::Ordering::Relaxed)
+        );
+    }
+
+    if cancel.is_cancelled() {
+        let not_started = progress.groups_not_started.into_inner();
+        println!(
+            "Cancelled: {} {} duplicate(s); {} group(s) stopped partway, {} of {} group(s) not started. Nothing was left half-replaced.",
+            if args.dry_run {
+                "would have replaced"
+            } else {
+                "replaced"
+            },
+            progress.replaced.into_inner(),
+            progress.groups_stopped.into_inner(),
+            not_started,
+            group_count
+        );
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Run cancelled; {} group(s) were not started.",
+                not_started
+            ));
+    }
+
+    staging.remove_empty_dirs();
+
+    if let Err(e) = journal.end() {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!("Failed to close the run journal: {}", e));
+    }
+
+    if !args.dry_run && args.link_type != args::LinkType::Dedupe {
+        println!(
+            "Backups for run {}: {}",
+            staging.run_id(),
+            staging.describe()
+        );
+    }
 
     let collected_errors = {
         let mut guard = errors.lock().expect("Should be able to unwrap lock");
@@ -196,7 +381,7 @@ pub fn replace_duplicates_with_symlinks(
     Ok(())
 }
 
-fn confirm_hashes_match(elms: &[CzkawkaDuplicateJsonFormatElement]) -> bool {
+pub(crate) fn confirm_hashes_match(elms: &[CzkawkaDuplicateJsonFormatElement]) -> bool {
     elms.par_iter()
         .map(|e| &e.hash)
         .collect::<Vec<&String>>()
@@ -206,181 +391,1908 @@ fn confirm_hashes_match(elms: &[CzkawkaDuplicateJsonFormatElement]) -> bool {
 
 fn replace_files(
     args: &args::Args,
+    placement: &Placement,
+    staging: &StagingArea,
+    journal: &Journal,
+    progress: &Progress,
     elms: &[CzkawkaDuplicateJsonFormatElement],
     errors: &std::sync::Mutex<Vec<Error>>,
 ) {
-    let original_file = choose_original_file(args, elms);
-    let original_path = std::path::Path::new(&original_file.path);
+    let targets = match resolve_targets(args, placement, elms) {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(e);
+            return;
+        }
+    };
+
+    for target in &targets {
+        // Vault objects are named by the BLAKE3 of their content.
+        let hash = match placement {
+            Placement::Vault(_) => target
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default(),
+            _ => elms[0].hash.as_str(),
+        };
+        journal.report_hash(&target.path, elms[0].size as u64, hash);
+    }
 
-    for duplicate in elms {
-        let duplicate_path = std::path::Path::new(&duplicate.path);
+    let mut pending = Vec::new();
+    let mut group_failed = false;
 
-        // Skip the original file
-        if duplicate.path == original_file.path {
+    let mut touched = false;
+
+    for member in elms {
+        // Skip the files that survive
+        if targets
+            .iter()
+            .any(|target| member.path.as_str() == target.path.as_os_str())
+        {
             continue;
         }
 
-        if args.dry_run {
+        // Members left linked by an earlier run are reported, not replaced
+        // again, so re-applying a report is a no-op.
+        match already_deduplicated(args, &targets, member) {
+            Ok(Some((target, relation))) => {
+                println!(
+                    "Already deduplicated: '{}' {} '{}'",
+                    member.path,
+                    relation,
+                    target.path.display()
+                );
+                progress
+                    .already_deduplicated
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!(
+                    "Failed to compare '{}' with its original: {}",
+                    member.path, e
+                );
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Failed to compare '{}' with its original: {}",
+                        member.path,
+                        e
+                    ));
+                if args.atomic_groups {
+                    group_failed = true;
+                    break;
+                }
+                continue;
+            }
+        }
+
+        // Stop between replacements; an atomic group is rolled back below.
+        if progress.cancel.is_cancelled() {
+            let counter = if touched {
+                &progress.groups_stopped
+            } else {
+                &progress.groups_not_started
+            };
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            group_failed = args.atomic_groups;
+            break;
+        }
+        touched = true;
+
+        let target = nearest_target(args, &targets, member);
+
+        // The relocated target does not exist yet during a dry run, so there is
+        // nothing to probe for devices or relative paths.
+        if args.dry_run && target.relocated {
             println!(
-                "[Dry Run] Would replace '{}' with symlink to '{}'",
-                duplicate_path.display(),
-                original_path.display()
+                "[Dry Run] Would replace '{}' with {} to '{}'",
+                member.path,
+                link_type_name(args.link_type),
+                target.path.display()
             );
+            progress.count_replaced();
             continue;
         }
 
-        let backup_path = match move_to_backup(duplicate_path) {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!(
-                    "Failed to stage duplicate file '{}' for replacement: {}",
-                    duplicate_path.display(),
-                    e
-                );
+        let outcome = replace_duplicate(
+            args,
+            staging,
+            journal,
+            &target.path,
+            target.canonical_path.as_deref(),
+            std::path::Path::new(&member.path),
+            errors,
+        );
+
+        match outcome {
+            ReplaceOutcome::Swapped(swapped) if args.atomic_groups => pending.push(swapped),
+            ReplaceOutcome::Swapped(swapped) => {
+                finish_swap(journal, swapped, args.durable, errors);
+                progress.count_replaced();
+            }
+            ReplaceOutcome::Done if args.dry_run => progress.count_replaced(),
+            ReplaceOutcome::Deduped {
+                shared_bytes,
+                complete,
+            } => {
+                progress
+                    .shared_bytes
+                    .fetch_add(shared_bytes, std::sync::atomic::Ordering::Relaxed);
+                if complete {
+                    progress.count_replaced();
+                }
+            }
+            ReplaceOutcome::Skipped | ReplaceOutcome::Failed if args.atomic_groups => {
+                group_failed = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    if !group_failed {
+        for swapped in pending {
+            finish_swap(journal, swapped, args.durable, errors);
+            progress.count_replaced();
+        }
+        return;
+    }
+
+    // With --atomic-groups a single failure undoes the whole group.
+    let restored = pending
+        .iter()
+        .filter(|swapped| roll_back_swap(journal, swapped, args.durable, errors))
+        .count();
+
+    if !args.dry_run && matches!(placement, Placement::CanonicalDir(_)) {
+        for target in targets.iter().filter(|target| target.relocated) {
+            if let Err(e) = std::fs::remove_file(&target.path) {
                 errors
                     .lock()
                     .expect("Should be able to unwrap lock")
                     .push(anyhow::anyhow!(
-                        "Failed to stage duplicate file '{}' for replacement: {}",
-                        duplicate_path.display(),
+                        "Rolled back group but failed to remove relocated copy '{}': {}",
+                        target.path.display(),
                         e
                     ));
+            }
+        }
+    }
+
+    println!(
+        "Rolled back group {}: restored {} of {} replaced member(s)",
+        elms[0].hash,
+        restored,
+        pending.len()
+    );
+}
+
+/// What makes two paths the same physical file: the device and inode where
+/// the platform exposes them, the canonical path otherwise.
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) enum SameFileKey {
+    Id(journal::FileId),
+    Path(std::path::PathBuf),
+}
+
+impl SameFileKey {
+    /// The file `path` names. A symlink is a file of its own, so it never
+    /// matches its target; directory links along the way are followed.
+    fn of_entry(path: &std::path::Path) -> Result<Self, std::io::Error> {
+        match journal::file_id(path)? {
+            Some(id) => Ok(Self::Id(id)),
+            None => Ok(Self::Path(plan::entry_key(path))),
+        }
+    }
+
+    /// The file `path` leads to once every symlink is followed.
+    pub(crate) fn of_target(path: &std::path::Path) -> Result<Self, std::io::Error> {
+        match journal::resolved_file_id(path)? {
+            Some(id) => Ok(Self::Id(id)),
+            None => Ok(Self::Path(std::fs::canonicalize(path)?)),
+        }
+    }
+}
+
+/// Whether `a` and `b` are one file reached through two paths (hard links,
+/// bind mounts, symlinked directories).
+pub(crate) fn same_file(a: &std::path::Path, b: &std::path::Path) -> Result<bool, std::io::Error> {
+    Ok(SameFileKey::of_entry(a)? == SameFileKey::of_entry(b)?)
+}
+
+/// Apply `--existing-symlinks` to members that are symlinks to a file outside
+/// the group. Symlinks to another member, or into the vault or canonical
+/// directory, are kept: they end up pointing straight at the original or are
+/// reported as already deduplicated. Returns `None` when the group must be
+/// left alone.
+fn apply_existing_symlinks(
+    args: &args::Args,
+    placement: &Placement,
+    group: Vec<CzkawkaDuplicateJsonFormatElement>,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> Option<Vec<CzkawkaDuplicateJsonFormatElement>> {
+    let is_symlink = |entry: &CzkawkaDuplicateJsonFormatElement| {
+        std::fs::symlink_metadata(&entry.path)
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    };
+    let real_files = group
+        .iter()
+        .filter(|entry| !is_symlink(entry))
+        .filter_map(|entry| entry.canonical_path.clone())
+        .collect::<Vec<std::path::PathBuf>>();
+
+    let mut kept = Vec::new();
+
+    for entry in group {
+        // Every member passed the allow-root check, which resolved its links.
+        let target = match &entry.canonical_path {
+            Some(target) if is_symlink(&entry) => target.clone(),
+            _ => {
+                kept.push(entry);
                 continue;
             }
         };
 
-        let symlink_result = create_symlink(original_path, duplicate_path);
+        let into_placement = match placement {
+            Placement::InPlace => false,
+            Placement::Vault(dir) | Placement::CanonicalDir(dir) => target.starts_with(dir),
+        };
 
-        match symlink_result {
-            Ok(_) => {
-                if let Err(e) = std::fs::remove_file(&backup_path) {
-                    eprintln!(
-                        "Symlinked '{}' but failed to delete backup '{}': {}",
-                        duplicate_path.display(),
-                        backup_path.display(),
-                        e
-                    );
-                    errors
-                        .lock()
-                        .expect("Should be able to unwrap lock")
-                        .push(anyhow::anyhow!(
-                            "Symlinked '{}' but failed to delete backup '{}': {}",
-                            duplicate_path.display(),
-                            backup_path.display(),
-                            e
-                        ));
-                }
+        if real_files.contains(&target) || into_placement {
+            kept.push(entry);
+            continue;
+        }
 
+        match args.existing_symlinks {
+            args::ExistingSymlinks::Skip => {
                 println!(
-                    "Replaced '{}' with symlink to '{}'",
-                    duplicate_path.display(),
-                    original_path.display()
+                    "Skipped '{}': it is a symlink to '{}', which is not in its group",
+                    entry.path,
+                    target.display()
                 );
             }
-            Err(e) => {
+            args::ExistingSymlinks::Error => {
                 eprintln!(
-                    "Failed to create symlink from '{}' to '{}': {}",
-                    duplicate_path.display(),
-                    original_path.display(),
-                    e
+                    "'{}' is a symlink to '{}', which is not in its group; the group was left alone",
+                    entry.path,
+                    target.display()
                 );
                 errors
                     .lock()
                     .expect("Should be able to unwrap lock")
                     .push(anyhow::anyhow!(
-                        "Failed to create symlink from '{}' to '{}': {}",
-                        duplicate_path.display(),
-                        original_path.display(),
-                        e
+                        "'{}' is a symlink to '{}', which is not in its group; the group was left alone",
+                        entry.path,
+                        target.display()
                     ));
+                return None;
+            }
+            args::ExistingSymlinks::Resolve => {
+                let already_folded = kept.iter().any(|e: &CzkawkaDuplicateJsonFormatElement| {
+                    e.canonical_path.as_ref() == Some(&target) && !is_symlink(e)
+                });
 
-                if let Err(restore_err) = std::fs::rename(&backup_path, duplicate_path) {
-                    eprintln!(
-                        "Also failed to restore original file from backup '{}': {}",
-                        backup_path.display(),
-                        restore_err
+                if !already_folded {
+                    println!(
+                        "Resolved '{}' to its target '{}', which joins the group",
+                        entry.path,
+                        target.display()
                     );
-                    errors
-                        .lock()
-                        .expect("Should be able to unwrap lock")
-                        .push(anyhow::anyhow!(
-                            "Also failed to restore original file from backup '{}': {}",
-                            backup_path.display(),
-                            restore_err
-                        ));
+                    kept.push(CzkawkaDuplicateJsonFormatElement {
+                        path: target.to_string_lossy().into_owned(),
+                        canonical_path: Some(target),
+                        ..entry.clone()
+                    });
                 }
+
+                kept.push(entry);
             }
         }
     }
+
+    Some(kept)
 }
 
-fn create_symlink(
-    original_path: &std::path::Path,
-    duplicate_path: &std::path::Path,
-) -> Result<(), std::io::Error> {
-    #[cfg(target_family = "windows")]
-    let original_path = original_path.canonicalize()?;
+/// How a member already shares its data with one of the group's targets:
+/// through a symlink, as the same file (hard link, bind mount), or as a
+/// reflink sharing every extent. Such members are reported as already
+/// deduplicated instead of being replaced again.
+fn already_deduplicated<'a>(
+    args: &args::Args,
+    targets: &'a [LinkTarget],
+    member: &CzkawkaDuplicateJsonFormatElement,
+) -> Result<Option<(&'a LinkTarget, &'static str)>, std::io::Error> {
+    let member_path = std::path::Path::new(&member.path);
 
-    #[cfg(target_family = "unix")]
-    {
-        std::os::unix::fs::symlink(original_path, duplicate_path)
+    if let Some(target) = targets.iter().find(|target| {
+        member.canonical_path.is_some() && member.canonical_path == target.canonical_path
+    }) {
+        return Ok(Some((target, "links to")));
     }
 
-    #[cfg(target_family = "windows")]
+    // A relocated target is a new name for the original's content, so it is
+    // the same file as its old path until that path becomes a link; only a
+    // hard-link run leaves it that way. During a dry run it does not exist yet.
+    for target in targets
+        .iter()
+        .filter(|target| !(target.relocated && args.dry_run))
     {
-        std::os::windows::fs::symlink_file(&original_path, duplicate_path)
+        if same_file(&target.path, member_path)? {
+            if !target.relocated || args.link_type == args::LinkType::Hardlink {
+                return Ok(Some((target, "is the same file as")));
+            }
+            continue;
+        }
+
+        if matches!(
+            args.link_type,
+            args::LinkType::Reflink | args::LinkType::Auto | args::LinkType::Dedupe
+        ) && clone::shares_extents(&target.path, member_path).unwrap_or(false)
+        {
+            return Ok(Some((target, "already shares every extent with")));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Cancellation and counters shared by the workers of one run, for the
+/// summary printed when the run is cancelled.
+struct Progress<'a> {
+    cancel: &'a CancellationToken,
+    /// Duplicates (and directories) whose replacement completed.
+    replaced: std::sync::atomic::AtomicUsize,
+    /// Groups the cancellation interrupted between two members.
+    groups_stopped: std::sync::atomic::AtomicUsize,
+    groups_not_started: std::sync::atomic::AtomicUsize,
+    /// Duplicates that already linked to (or shared data with) their target.
+    already_deduplicated: std::sync::atomic::AtomicUsize,
+    /// Bytes the kernel shared in place with `--link-type dedupe`.
+    shared_bytes: std::sync::atomic::AtomicU64,
+}
+
+impl Progress<'_> {
+    fn count_replaced(&self) {
+        self.replaced
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 }
 
-fn move_to_backup(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
-    let mut counter = 0u32;
-    loop {
-        let suffix = if counter == 0 {
-            "czkawka-bak".to_string()
-        } else {
-            format!("czkawka-bak-{}", counter)
-        };
+/// Where the surviving copy of each duplicate group lives.
+enum Placement {
+    /// Keep the chosen original where it is.
+    InPlace,
+    /// Move the chosen original into this content-addressed vault directory.
+    Vault(std::path::PathBuf),
+    /// Move the chosen original under this directory, keeping its path
+    /// relative to its allow-root, unless a member already lives there.
+    CanonicalDir(std::path::PathBuf),
+}
+
+/// A file that survives as a real copy and that other members link to.
+struct LinkTarget {
+    path: std::path::PathBuf,
+    canonical_path: Option<std::path::PathBuf>,
+    /// Set when the content was (or, in a dry run, would be) moved away from
+    /// the chosen original, so the original path must be linked as well.
+    relocated: bool,
+}
 
-        let candidate = path.with_extension(suffix);
+fn resolve_placement(args: &args::Args) -> Result<Placement, Error> {
+    let keeps_extra_copies = args.keep_copies.is_some_and(|copies| copies > 1);
 
-        if !candidate.exists() {
-            std::fs::rename(path, &candidate)?;
-            return Ok(candidate);
+    if args.dedupe_directories {
+        if !matches!(
+            args.link_type,
+            args::LinkType::Symlink | args::LinkType::Auto
+        ) {
+            anyhow::bail!("--dedupe-directories requires --link-type symlink or auto.");
         }
 
-        counter += 1;
+        if keeps_extra_copies || args.vault.is_some() || args.canonical_dir.is_some() {
+            anyhow::bail!(
+                "--dedupe-directories cannot be combined with --keep-copies, --vault or --canonical-dir."
+            );
+        }
     }
-}
 
-fn ensure_path_within_roots(path: &str, allowed_roots: &[std::path::PathBuf]) -> Result<(), Error> {
-    if allowed_roots.is_empty() {
-        anyhow::bail!("No allow-root paths configured.");
+    if args.atomic_groups && args.link_type == args::LinkType::Dedupe {
+        anyhow::bail!(
+            "--atomic-groups cannot be combined with --link-type dedupe, which never stages backups."
+        );
     }
 
-    let canonical_path = std::fs::canonicalize(path)
-        .with_context(|| format!("Failed to canonicalize path '{}'.", path))?;
+    let (directory, flag) = match (&args.vault, &args.canonical_dir) {
+        (None, None) => return Ok(Placement::InPlace),
+        _ if keeps_extra_copies => {
+            anyhow::bail!("--keep-copies cannot be combined with --vault or --canonical-dir.");
+        }
+        (Some(_), Some(_)) => {
+            anyhow::bail!("--vault and --canonical-dir cannot be combined.");
+        }
+        (Some(vault), None) => (vault, "--vault"),
+        (None, Some(canonical_dir)) => (canonical_dir, "--canonical-dir"),
+    };
 
-    let is_allowed = allowed_roots
+    if args.link_type == args::LinkType::Dedupe {
+        anyhow::bail!("{} cannot be combined with --link-type dedupe.", flag);
+    }
+
+    let resolved = canonicalize_destination(directory).with_context(|| {
+        format!(
+            "Failed to resolve {} directory '{}'.",
+            flag,
+            directory.display()
+        )
+    })?;
+
+    if !args
+        .allow_roots
         .iter()
-        .any(|root| canonical_path.starts_with(root));
+        .any(|root| resolved.starts_with(root))
+    {
+        return Err(outside_roots_error(
+            &resolved.display().to_string(),
+            &args.allow_roots,
+        ))
+        .with_context(|| format!("The {} directory must live inside an allow-root.", flag));
+    }
 
-    if is_allowed {
-        Ok(())
+    if args.vault.is_some() {
+        Ok(Placement::Vault(resolved))
     } else {
-        let roots = allowed_roots
-            .iter()
-            .map(|root| root.display().to_string())
-            .collect::<Vec<String>>()
-            .join(", ");
-        anyhow::bail!(
-            "Path '{}' is outside the configured allow-root directories: {}",
-            path,
-            roots
-        );
+        Ok(Placement::CanonicalDir(resolved))
     }
 }
 
-fn choose_original_file<'a>(
+/// Pick (and, for vault or canonical-dir runs, relocate) the files that stay
+/// real; every other member of the group links to one of them. Members that
+/// are symlinks themselves are never picked, so no link points at a link, and
+/// two names of one file count as one candidate.
+fn resolve_targets(
+    args: &args::Args,
+    placement: &Placement,
+    elms: &[CzkawkaDuplicateJsonFormatElement],
+) -> Result<Vec<LinkTarget>, Error> {
+    let mut seen = Vec::new();
+    let real_files = elms
+        .iter()
+        .filter(|e| {
+            std::fs::symlink_metadata(&e.path).is_ok_and(|metadata| metadata.file_type().is_file())
+        })
+        // Two names of one file (hard links, bind mounts) are one candidate.
+        .filter(
+            |e| match SameFileKey::of_entry(std::path::Path::new(&e.path)) {
+                Ok(key) if seen.contains(&key) => false,
+                Ok(key) => {
+                    seen.push(key);
+                    true
+                }
+                Err(_) => true,
+            },
+        )
+        .cloned()
+        .collect::<Vec<CzkawkaDuplicateJsonFormatElement>>();
+
+    if real_files.is_empty() {
+        anyhow::bail!(
+            "No member of the group is a real file to link to: {:?}",
+            elms.iter().map(|e| &e.path).collect::<Vec<&String>>()
+        );
+    }
+    let elms = real_files.as_slice();
+
+    let in_place = |original: &CzkawkaDuplicateJsonFormatElement| LinkTarget {
+        path: std::path::PathBuf::from(&original.path),
+        canonical_path: original.canonical_path.clone(),
+        relocated: false,
+    };
+
+    match placement {
+        Placement::InPlace => Ok(choose_kept_copies(args, elms)
+            .into_iter()
+            .map(in_place)
+            .collect()),
+        Placement::Vault(vault_dir) => {
+            let original_file = choose_original_file(args, elms);
+            let object_path = store_in_vault(args, vault_dir, original_file)?;
+
+            Ok(vec![LinkTarget {
+                canonical_path: Some(object_path.clone()),
+                path: object_path,
+                relocated: true,
+            }])
+        }
+        Placement::CanonicalDir(canonical_dir) => {
+            let already_there = elms
+                .iter()
+                .filter(|e| {
+                    e.canonical_path
+                        .as_ref()
+                        .is_some_and(|path| path.starts_with(canonical_dir))
+                })
+                .cloned()
+                .collect::<Vec<CzkawkaDuplicateJsonFormatElement>>();
+
+            if !already_there.is_empty() {
+                return Ok(vec![in_place(choose_original_file(args, &already_there))]);
+            }
+
+            let original_file = choose_original_file(args, elms);
+            let destination = move_to_canonical_dir(args, canonical_dir, original_file)?;
+
+            Ok(vec![LinkTarget {
+                canonical_path: Some(destination.clone()),
+                path: destination,
+                relocated: true,
+            }])
+        }
+    }
+}
+
+/// Choose the members that stay real files: the usual original first, then
+/// (with `--keep-copies N`) further members on devices or allow-roots not yet
+/// covered, topping up from the remaining members if there are too few.
+fn choose_kept_copies<'a>(
+    args: &args::Args,
+    elms: &'a [CzkawkaDuplicateJsonFormatElement],
+) -> Vec<&'a CzkawkaDuplicateJsonFormatElement> {
+    let wanted = args.keep_copies.unwrap_or(1).max(1) as usize;
+    let primary = choose_original_file(args, elms);
+    let mut kept = vec![primary];
+
+    while kept.len() < wanted.min(elms.len()) {
+        let covered = kept.iter().map(|e| spread_key(args, e)).collect::<Vec<_>>();
+        let remaining = elms
+            .iter()
+            .filter(|e| !kept.iter().any(|k| k.path == e.path))
+            .cloned()
+            .collect::<Vec<CzkawkaDuplicateJsonFormatElement>>();
+        let spread = remaining
+            .iter()
+            .filter(|e| {
+                let key = spread_key(args, e);
+                key.is_some() && !covered.contains(&key)
+            })
+            .cloned()
+            .collect::<Vec<CzkawkaDuplicateJsonFormatElement>>();
+
+        let candidates = if spread.is_empty() {
+            &remaining
+        } else {
+            &spread
+        };
+        let chosen_path = choose_original_file(args, candidates).path.clone();
+        let chosen = elms
+            .iter()
+            .find(|e| e.path == chosen_path)
+            .expect("Chosen copy comes from the group");
+
+        println!("Keeping '{}' as an additional real copy", chosen.path);
+        kept.push(chosen);
+    }
+
+    kept
+}
+
+/// What kept copies are spread across: a device id or the containing
+/// allow-root, depending on `--spread-by`.
+fn spread_key(args: &args::Args, entry: &CzkawkaDuplicateJsonFormatElement) -> Option<String> {
+    match args.spread_by {
+        args::SpreadBy::Device => device_key(std::path::Path::new(&entry.path)),
+        args::SpreadBy::Root => root_key(args, entry),
+    }
+}
+
+fn device_key(path: &std::path::Path) -> Option<String> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        std::fs::metadata(path)
+            .ok()
+            .map(|metadata| metadata.dev().to_string())
+    }
+
+    // Stable Rust exposes no volume id on Windows; the drive prefix of the
+    // canonical path is the closest stand-in.
+    #[cfg(target_family = "windows")]
+    {
+        std::fs::canonicalize(path).ok().and_then(|path| {
+            path.components()
+                .next()
+                .map(|prefix| prefix.as_os_str().to_string_lossy().into_owned())
+        })
+    }
+}
+
+fn root_key(args: &args::Args, entry: &CzkawkaDuplicateJsonFormatElement) -> Option<String> {
+    let canonical = entry.canonical_path.as_ref()?;
+
+    args.allow_roots
+        .iter()
+        .filter(|root| canonical.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .map(|root| root.display().to_string())
+}
+
+/// The kept copy a duplicate should link to: one sharing its `--spread-by`
+/// key (device or allow-root) if any, then one on the same device, then the
+/// primary original.
+fn nearest_target<'a>(
+    args: &args::Args,
+    targets: &'a [LinkTarget],
+    member: &CzkawkaDuplicateJsonFormatElement,
+) -> &'a LinkTarget {
+    if targets.len() == 1 {
+        return &targets[0];
+    }
+
+    let member_root = root_key(args, member);
+    let same_root = |target: &LinkTarget| {
+        member_root.as_ref().is_some_and(|root| {
+            target
+                .canonical_path
+                .as_ref()
+                .is_some_and(|canonical| canonical.starts_with(root))
+        })
+    };
+
+    let member_device = device_key(std::path::Path::new(&member.path));
+    let same_device =
+        |target: &LinkTarget| member_device.is_some() && device_key(&target.path) == member_device;
+
+    let preferred = match args.spread_by {
+        args::SpreadBy::Device => targets.iter().find(|target| same_device(target)),
+        args::SpreadBy::Root => targets
+            .iter()
+            .find(|target| same_root(target))
+            .or_else(|| targets.iter().find(|target| same_device(target))),
+    };
+
+    preferred.unwrap_or(&targets[0])
+}
+
+/// Give the chosen original a name under `canonical_dir` that mirrors its path
+/// relative to its allow-root, and return that new path.
+fn move_to_canonical_dir(
+    args: &args::Args,
+    canonical_dir: &std::path::Path,
+    original_file: &CzkawkaDuplicateJsonFormatElement,
+) -> Result<std::path::PathBuf, Error> {
+    let canonical_original = match &original_file.canonical_path {
+        Some(path) => path.clone(),
+        None => std::fs::canonicalize(&original_file.path)
+            .with_context(|| format!("Failed to canonicalize path '{}'.", original_file.path))?,
+    };
+
+    let relative = args
+        .allow_roots
+        .iter()
+        .filter(|root| canonical_original.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .and_then(|root| canonical_original.strip_prefix(root).ok())
+        .ok_or_else(|| outside_roots_error(&original_file.path, &args.allow_roots))?;
+
+    let destination = canonical_dir.join(relative);
+
+    if args.dry_run {
+        println!(
+            "[Dry Run] Would move '{}' to '{}'",
+            original_file.path,
+            destination.display()
+        );
+        return Ok(destination);
+    }
+
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory '{}'.", parent.display()))?;
+    }
+
+    relocate_original(
+        std::path::Path::new(&original_file.path),
+        &destination,
+        args.durable,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to move '{}' to '{}'; existing files are never overwritten.",
+            original_file.path,
+            destination.display()
+        )
+    })?;
+
+    if args.durable {
+        sync_new_entry(&destination, canonical_dir)?;
+    }
+
+    println!(
+        "Moved '{}' to '{}'",
+        original_file.path,
+        destination.display()
+    );
+
+    Ok(destination)
+}
+
+/// Canonicalize a directory that may not exist yet by resolving its parent.
+fn canonicalize_destination(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+    if path.exists() {
+        return std::fs::canonicalize(path);
+    }
+
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "path has no parent directory",
+        ));
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        std::path::Path::new(".")
+    } else {
+        parent
+    };
+
+    Ok(std::fs::canonicalize(parent)?.join(name))
+}
+
+/// Path of the vault object for the BLAKE3 digest `hash`, fanned out as
+/// `<vault>/blake3/ab/cd/<hash>`. `None` unless `hash` is 64 hex digits.
+fn vault_object_path(vault_dir: &std::path::Path, hash: &str) -> Option<std::path::PathBuf> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let hash = hash.to_ascii_lowercase();
+    Some(
+        vault_dir
+            .join("blake3")
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(&hash),
+    )
+}
+
+/// Make sure the vault holds the content of `original_file` and return the
+/// object path. Objects are named by a BLAKE3 of their bytes. A BLAKE3 report
+/// hash is only a hint: the object it names is reused if its bytes match the
+/// original's, and otherwise the original is hashed to find its name, since
+/// reports can be stale and CRC32 or XXH3 hashes can collide. The original
+/// path itself is left for the caller to replace with a link.
+fn store_in_vault(
+    args: &args::Args,
+    vault_dir: &std::path::Path,
+    original_file: &CzkawkaDuplicateJsonFormatElement,
+) -> Result<std::path::PathBuf, Error> {
+    let original_path = std::path::Path::new(&original_file.path);
+
+    if let Some(hinted) = vault_object_path(vault_dir, &original_file.hash)
+        && std::fs::symlink_metadata(&hinted).is_ok()
+        && ensure_vault_object_matches(&hinted, original_path).is_ok()
+    {
+        println!(
+            "Reusing vault object '{}' for '{}'",
+            hinted.display(),
+            original_file.path
+        );
+        return Ok(hinted);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    std::fs::File::open(original_path)
+        .and_then(|mut file| std::io::copy(&mut file, &mut hasher))
+        .with_context(|| format!("Failed to hash '{}' for the vault.", original_file.path))?;
+    let object_path = vault_object_path(vault_dir, hasher.finalize().to_hex().as_str())
+        .expect("BLAKE3 digests are 64 hex digits");
+
+    match std::fs::symlink_metadata(&object_path) {
+        Ok(_) => {
+            ensure_vault_object_matches(&object_path, original_path)?;
+            println!(
+                "Reusing vault object '{}' for '{}'",
+                object_path.display(),
+                original_file.path
+            );
+            return Ok(object_path);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to inspect vault object '{}'.",
+                    object_path.display()
+                )
+            });
+        }
+    }
+
+    if args.dry_run {
+        println!(
+            "[Dry Run] Would move '{}' into vault object '{}'",
+            original_file.path,
+            object_path.display()
+        );
+        return Ok(object_path);
+    }
+
+    let object_dir = object_path
+        .parent()
+        .expect("Vault object paths always have a parent");
+    std::fs::create_dir_all(object_dir).with_context(|| {
+        format!(
+            "Failed to create vault directory '{}'.",
+            object_dir.display()
+        )
+    })?;
+
+    match relocate_original(original_path, &object_path, args.durable) {
+        Ok(()) => println!(
+            "Moved '{}' into vault object '{}'",
+            original_file.path,
+            object_path.display()
+        ),
+        // Another group with the same content got there first.
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            ensure_vault_object_matches(&object_path, original_path)?;
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to move '{}' into vault object '{}'.",
+                    original_file.path,
+                    object_path.display()
+                )
+            });
+        }
+    }
+
+    if args.durable {
+        sync_new_entry(&object_path, vault_dir)?;
+    }
+
+    Ok(object_path)
+}
+
+/// Fsync the directories from `path` up to `base` (and the directory holding
+/// `base`, which may have been created too), so links made to `path` never
+/// outlive it after a power loss.
+fn sync_new_entry(path: &std::path::Path, base: &std::path::Path) -> Result<(), Error> {
+    rename::sync_parents_up_to(path, base.parent().unwrap_or(base))
+        .with_context(|| format!("Failed to make '{}' durable.", path.display()))
+}
+
+/// Refuse an existing vault object unless it is a regular file holding exactly
+/// the bytes of `original_path`. Linking a group to an object with other
+/// content would lose the group's data once its backups are gone.
+fn ensure_vault_object_matches(
+    object_path: &std::path::Path,
+    original_path: &std::path::Path,
+) -> Result<(), Error> {
+    let metadata = std::fs::symlink_metadata(object_path).with_context(|| {
+        format!(
+            "Failed to inspect vault object '{}'.",
+            object_path.display()
+        )
+    })?;
+
+    if !metadata.is_file() {
+        anyhow::bail!(
+            "Vault object '{}' is not a regular file.",
+            object_path.display()
+        );
+    }
+
+    let original_len = std::fs::metadata(original_path)
+        .with_context(|| format!("Failed to read metadata for '{}'.", original_path.display()))?
+        .len();
+
+    if metadata.len() != original_len {
+        anyhow::bail!(
+            "Vault object '{}' holds {} bytes but the duplicate group has {} bytes.",
+            object_path.display(),
+            metadata.len(),
+            original_len
+        );
+    }
+
+    // A rerun's original is often already a link to the object.
+    let same_file = matches!(
+        (
+            journal::resolved_file_id(object_path),
+            journal::resolved_file_id(original_path),
+        ),
+        (Ok(Some(object)), Ok(Some(original))) if object == original
+    );
+
+    let matches = same_file
+        || same_content(object_path, original_path).with_context(|| {
+            format!(
+                "Failed to compare vault object '{}' with '{}'.",
+                object_path.display(),
+                original_path.display()
+            )
+        })?;
+
+    if !matches {
+        anyhow::bail!(
+            "Vault object '{}' is named for the content of '{}' but holds different content; the group was left alone.",
+            object_path.display(),
+            original_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether two files hold the same bytes, read side by side in 1 MiB chunks.
+pub(crate) fn same_content(
+    a: &std::path::Path,
+    b: &std::path::Path,
+) -> Result<bool, std::io::Error> {
+    use std::io::Read;
+
+    let mut a = std::fs::File::open(a)?;
+    let mut b = std::fs::File::open(b)?;
+    let mut a_buffer = vec![0u8; 1 << 20];
+    let mut b_buffer = vec![0u8; 1 << 20];
+
+    loop {
+        let read = a.read(&mut a_buffer)?;
+        if read == 0 {
+            // Both must end together.
+            return Ok(b.read(&mut b_buffer[..1])? == 0);
+        }
+
+        let chunk = &mut b_buffer[..read];
+        match b.read_exact(chunk) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        if a_buffer[..read] != *chunk {
+            return Ok(false);
+        }
+    }
+}
+
+/// Give the content of `original_path` a second name at `destination` without
+/// touching `original_path`, which is later replaced by a link like any other
+/// duplicate. Never overwrites `destination`; across devices the content is
+/// copied instead.
+fn relocate_original(
+    original_path: &std::path::Path,
+    destination: &std::path::Path,
+    durable: bool,
+) -> Result<(), std::io::Error> {
+    match std::fs::hard_link(original_path, destination) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let mut temp_name = destination.as_os_str().to_owned();
+            temp_name.push(format!(".czkawka-tmp-{}", std::process::id()));
+            let temp_path = std::path::PathBuf::from(temp_name);
+
+            std::fs::copy(original_path, &temp_path)?;
+            if durable && let Err(e) = rename::sync_file(&temp_path) {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(e);
+            }
+            let result = std::fs::hard_link(&temp_path, destination);
+            let _ = std::fs::remove_file(&temp_path);
+            result
+        }
+        other => other,
+    }
+}
+
+/// What happened to one duplicate in [`replace_duplicate`].
+enum ReplaceOutcome {
+    /// Nothing is waiting on the caller: a dry run.
+    Done,
+    /// The kernel shared `shared_bytes` of the duplicate's extents in place.
+    /// `complete` is unset when some ranges were refused; that error has been
+    /// recorded.
+    Deduped { shared_bytes: u64, complete: bool },
+    /// The duplicate was deliberately left alone (e.g. a cross-device hard link).
+    Skipped,
+    /// The link is in place and the replaced file still waits at its backup.
+    Swapped(SwappedLink),
+    /// The duplicate is untouched and the error has been recorded.
+    Failed,
+}
+
+/// A duplicate that now shows its link while the replaced file is still kept
+/// at `backup_path`, so the swap can be finished or rolled back.
+struct SwappedLink {
+    duplicate_path: std::path::PathBuf,
+    backup_path: std::path::PathBuf,
+    /// Journal entry of the swap.
+    entry: u64,
+    /// Printed once the backup is gone.
+    message: String,
+}
+
+/// Swap a link to `original_path` in place of `duplicate_path`, keeping the
+/// replaced file as a backup for the caller to finish or roll back.
+fn replace_duplicate(
+    args: &args::Args,
+    staging: &StagingArea,
+    journal: &Journal,
+    original_path: &std::path::Path,
+    original_canonical: Option<&std::path::Path>,
+    duplicate_path: &std::path::Path,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> ReplaceOutcome {
+    if args.link_type == args::LinkType::Hardlink {
+        match same_device(original_path, duplicate_path) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!(
+                    "Skipped '{}': hard links cannot cross devices and '{}' is on a different device",
+                    duplicate_path.display(),
+                    original_path.display()
+                );
+                return ReplaceOutcome::Skipped;
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to compare devices of '{}' and '{}': {}",
+                    duplicate_path.display(),
+                    original_path.display(),
+                    e
+                );
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Failed to compare devices of '{}' and '{}': {}",
+                        duplicate_path.display(),
+                        original_path.display(),
+                        e
+                    ));
+                return ReplaceOutcome::Failed;
+            }
+        }
+    }
+
+    let link_name = link_type_name(args.link_type);
+
+    let symlink_target = match args.link_type {
+        args::LinkType::Symlink | args::LinkType::Auto => {
+            match symlink_target(
+                args.symlink_target,
+                original_path,
+                original_canonical,
+                duplicate_path,
+            ) {
+                Ok(target) => target,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to compute symlink target from '{}' to '{}': {}",
+                        duplicate_path.display(),
+                        original_path.display(),
+                        e
+                    );
+                    errors
+                        .lock()
+                        .expect("Should be able to unwrap lock")
+                        .push(anyhow::anyhow!(
+                            "Failed to compute symlink target from '{}' to '{}': {}",
+                            duplicate_path.display(),
+                            original_path.display(),
+                            e
+                        ));
+                    return ReplaceOutcome::Failed;
+                }
+            }
+        }
+        _ => original_path.to_path_buf(),
+    };
+
+    if args.dry_run && args.link_type == args::LinkType::Dedupe {
+        println!(
+            "[Dry Run] Would deduplicate extents of '{}' against '{}'",
+            duplicate_path.display(),
+            original_path.display()
+        );
+        return ReplaceOutcome::Done;
+    }
+
+    if args.dry_run && args.link_type == args::LinkType::Auto {
+        let fallback = match same_device(original_path, duplicate_path) {
+            Ok(true) => "hard link (same device)",
+            Ok(false) => "symlink (different devices)",
+            Err(_) => "symlink (devices could not be compared)",
+        };
+        println!(
+            "[Dry Run] Would replace '{}' with a reflink to '{}' if supported, otherwise a {}",
+            duplicate_path.display(),
+            original_path.display(),
+            fallback
+        );
+        return ReplaceOutcome::Done;
+    }
+
+    if args.dry_run {
+        println!(
+            "[Dry Run] Would replace '{}' with {} to '{}'",
+            duplicate_path.display(),
+            link_name,
+            symlink_target.display()
+        );
+        return ReplaceOutcome::Done;
+    }
+
+    if args.link_type == args::LinkType::Dedupe {
+        return dedupe_duplicate(original_path, duplicate_path, errors);
+    }
+
+    let link_result = swap_in_link(
+        staging,
+        journal,
+        args.durable,
+        args.link_type,
+        original_path,
+        &symlink_target,
+        duplicate_path,
+    );
+
+    match link_result {
+        Ok((placed, backup_path, entry)) => {
+            let shown_target = if placed.link_type == args::LinkType::Symlink {
+                symlink_target.as_path()
+            } else {
+                original_path
+            };
+
+            let message = match placed.reason {
+                Some(reason) => format!(
+                    "Replaced '{}' with {} to '{}' (auto: {})",
+                    duplicate_path.display(),
+                    link_type_name(placed.link_type),
+                    shown_target.display(),
+                    reason
+                ),
+                None => format!(
+                    "Replaced '{}' with {} to '{}'",
+                    duplicate_path.display(),
+                    link_name,
+                    shown_target.display()
+                ),
+            };
+
+            ReplaceOutcome::Swapped(SwappedLink {
+                duplicate_path: duplicate_path.to_path_buf(),
+                backup_path,
+                entry,
+                message,
+            })
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to create {} from '{}' to '{}': {}",
+                link_name,
+                duplicate_path.display(),
+                original_path.display(),
+                e
+            );
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(anyhow::anyhow!(
+                    "Failed to create {} from '{}' to '{}': {}",
+                    link_name,
+                    duplicate_path.display(),
+                    original_path.display(),
+                    e
+                ));
+
+            ReplaceOutcome::Failed
+        }
+    }
+}
+
+/// Delete the backup of a swapped duplicate and report the replacement.
+fn finish_swap(
+    journal: &Journal,
+    swapped: SwappedLink,
+    durable: bool,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) {
+    // The backup is the only other copy of the replaced file's name, so it
+    // stays until the link itself is on disk.
+    if durable
+        && let Err(e) = rename::sync_parents(&[&swapped.duplicate_path, &swapped.backup_path])
+    {
+        eprintln!(
+            "Linked '{}' but kept backup '{}' because the link could not be made durable: {}",
+            swapped.duplicate_path.display(),
+            swapped.backup_path.display(),
+            e
+        );
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Linked '{}' but kept backup '{}' because the link could not be made durable: {}",
+                swapped.duplicate_path.display(),
+                swapped.backup_path.display(),
+                e
+            ));
+        return;
+    }
+
+    if let Err(e) = journal.commit(swapped.entry) {
+        eprintln!(
+            "Linked '{}' but kept backup '{}' because the journal could not be written: {}",
+            swapped.duplicate_path.display(),
+            swapped.backup_path.display(),
+            e
+        );
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Linked '{}' but kept backup '{}' because the journal could not be written: {}",
+                swapped.duplicate_path.display(),
+                swapped.backup_path.display(),
+                e
+            ));
+        return;
+    }
+
+    if let Err(e) = std::fs::remove_file(&swapped.backup_path) {
+        eprintln!(
+            "Linked '{}' but failed to delete backup '{}': {}",
+            swapped.duplicate_path.display(),
+            swapped.backup_path.display(),
+            e
+        );
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Linked '{}' but failed to delete backup '{}': {}",
+                swapped.duplicate_path.display(),
+                swapped.backup_path.display(),
+                e
+            ));
+    } else if durable && let Err(e) = rename::sync_parents(&[&swapped.backup_path]) {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Linked '{}' but the removal of backup '{}' could not be made durable: {}",
+                swapped.duplicate_path.display(),
+                swapped.backup_path.display(),
+                e
+            ));
+    } else if let Err(e) = journal.done(swapped.entry) {
+        errors
+            .lock()
+            .expect("Should be able to unwrap lock")
+            .push(anyhow::anyhow!(
+                "Linked '{}' but failed to record it in the journal: {}",
+                swapped.duplicate_path.display(),
+                e
+            ));
+    }
+
+    println!("{}", swapped.message);
+}
+
+/// Put the replaced file back over its link. The rename is atomic, so the path
+/// goes straight from the link to the original file.
+fn roll_back_swap(
+    journal: &Journal,
+    swapped: &SwappedLink,
+    durable: bool,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> bool {
+    // A journal failure here only risks an unneeded recovery prompt later; the
+    // restore itself must still happen.
+    let _ = journal.restore(swapped.entry);
+
+    match std::fs::rename(&swapped.backup_path, &swapped.duplicate_path) {
+        Ok(()) => {
+            if durable
+                && let Err(e) =
+                    rename::sync_parents(&[&swapped.duplicate_path, &swapped.backup_path])
+            {
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Restored '{}' but the restore could not be made durable: {}",
+                        swapped.duplicate_path.display(),
+                        e
+                    ));
+            }
+            let _ = journal.restored(swapped.entry);
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to restore '{}' from backup '{}': {}",
+                swapped.duplicate_path.display(),
+                swapped.backup_path.display(),
+                e
+            );
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(anyhow::anyhow!(
+                    "Failed to restore '{}' from backup '{}': {}",
+                    swapped.duplicate_path.display(),
+                    swapped.backup_path.display(),
+                    e
+                ));
+            false
+        }
+    }
+}
+
+/// Replace `duplicate_path` with the requested link without the path ever
+/// going missing, returning the backup name that now holds the replaced file.
+///
+/// The link is built under a free backup name on the duplicate's filesystem and the two
+/// entries are exchanged atomically. Where exchange is unavailable the
+/// duplicate is first hard-linked to a second backup name and the new link is
+/// renamed over it, which is equally atomic for readers of the path. With
+/// `durable` the new link (and any second backup name) is fsynced before the
+/// duplicate is touched. Every name is journaled before it is created, so a
+/// crash never leaves an entry the journal does not know about.
+fn swap_in_link(
+    staging: &StagingArea,
+    journal: &Journal,
+    durable: bool,
+    link_type: args::LinkType,
+    original_path: &std::path::Path,
+    symlink_target: &std::path::Path,
+    duplicate_path: &std::path::Path,
+) -> Result<(PlacedLink, std::path::PathBuf, u64), std::io::Error> {
+    let planned_target = matches!(link_type, args::LinkType::Symlink | args::LinkType::Auto)
+        .then_some(symlink_target);
+    let mut counter = 0;
+
+    let (placed, staged_link, entry) = loop {
+        let staged_link = staging.free_name(duplicate_path, &mut counter)?;
+        let entry = journal.stage(
+            duplicate_path,
+            original_path,
+            link_type_name(link_type),
+            planned_target,
+            &staged_link,
+        )?;
+
+        match create_link(
+            link_type,
+            original_path,
+            symlink_target,
+            duplicate_path,
+            &staged_link,
+        ) {
+            Ok(placed) => break (placed, staged_link, entry),
+            Err(e) => {
+                let _ = journal.abandoned(entry);
+                // Another worker took the name after it was found free.
+                if e.kind() != std::io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+                counter += 1;
+            }
+        }
+    };
+
+    let abandon = |paths: &[&std::path::Path], e: std::io::Error| {
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+        let _ = journal.abandoned(entry);
+        Err(e)
+    };
+
+    if durable {
+        let synced = if placed.link_type == args::LinkType::Reflink {
+            rename::sync_file(&staged_link)
+        } else {
+            Ok(())
+        }
+        .and_then(|()| rename::sync_parents(&[&staged_link, duplicate_path]));
+
+        if let Err(e) = synced {
+            return abandon(&[&staged_link], e);
+        }
+    }
+
+    let link_target = (placed.link_type == args::LinkType::Symlink).then_some(symlink_target);
+    if let Err(e) = journal.linked(
+        entry,
+        link_type_name(placed.link_type),
+        link_target,
+        &staged_link,
+    ) {
+        return abandon(&[&staged_link], e);
+    }
+
+    match rename::exchange(&staged_link, duplicate_path) {
+        Ok(()) => return Ok((placed, staged_link, entry)),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {}
+        Err(e) => return abandon(&[&staged_link], e),
+    }
+
+    let backup_path = loop {
+        let backup_path = match staging.free_name(duplicate_path, &mut counter) {
+            Ok(backup_path) => backup_path,
+            Err(e) => return abandon(&[&staged_link], e),
+        };
+
+        if let Err(e) = journal.backup(entry, &backup_path) {
+            return abandon(&[&staged_link], e);
+        }
+
+        match std::fs::hard_link(duplicate_path, &backup_path) {
+            Ok(()) => break backup_path,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return abandon(&[&staged_link], e),
+        }
+    };
+
+    if durable && let Err(e) = rename::sync_parents(&[&backup_path]) {
+        return abandon(&[&staged_link, &backup_path], e);
+    }
+
+    if let Err(e) = std::fs::rename(&staged_link, duplicate_path) {
+        return abandon(&[&staged_link, &backup_path], e);
+    }
+
+    Ok((placed, backup_path, entry))
+}
+
+/// Lock the allow-roots against overlapping runs. Dry runs change nothing and
+/// library callers without a lock or state directory run unlocked.
+fn lock_roots(
+    args: &args::Args,
+    run_id: &str,
+    cancel: &CancellationToken,
+) -> Result<Option<lock::RunLock>, Error> {
+    let Some(dir) = lock::lock_dir(args.lock_dir.as_deref(), args.state_dir.as_deref()) else {
+        return Ok(None);
+    };
+
+    if args.dry_run {
+        return Ok(None);
+    }
+
+    lock::acquire(&dir, &args.allow_roots, run_id, args.wait, cancel).map(Some)
+}
+
+/// Open this run's journal. Dry runs and library callers without a state
+/// directory run unjournaled.
+fn start_journal(args: &args::Args, run_id: &str) -> Result<Journal, Error> {
+    let Some(state_dir) = &args.state_dir else {
+        return Ok(Journal::disabled());
+    };
+
+    if args.dry_run {
+        return Ok(Journal::disabled());
+    }
+
+    let lock_dir = lock::lock_dir(args.lock_dir.as_deref(), Some(state_dir));
+    Journal::create(state_dir, run_id, &args.allow_roots, lock_dir.as_deref())
+}
+
+/// A per-run identifier that names hidden backups and staging entries. It is
+/// unique across processes (pid) and across runs inside one process.
+fn new_run_id() -> String {
+    static RUNS_IN_PROCESS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let sequence = RUNS_IN_PROCESS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    if sequence == 0 {
+        format!("{}-{}", seconds, std::process::id())
+    } else {
+        format!("{}-{}-{}", seconds, std::process::id(), sequence)
+    }
+}
+
+fn link_type_name(link_type: args::LinkType) -> &'static str {
+    match link_type {
+        args::LinkType::Symlink => "symlink",
+        args::LinkType::Hardlink => "hard link",
+        args::LinkType::Reflink => "reflink",
+        args::LinkType::Dedupe => "shared extents",
+        args::LinkType::Auto => "best available link",
+    }
+}
+
+fn existing_symlinks_name(policy: args::ExistingSymlinks) -> &'static str {
+    match policy {
+        args::ExistingSymlinks::Skip => "skip (left as they are)",
+        args::ExistingSymlinks::Resolve => "resolve (their targets join the group)",
+        args::ExistingSymlinks::Error => "error (their group is left alone)",
+    }
+}
+
+fn symlink_target_name(mode: args::SymlinkTarget) -> &'static str {
+    match mode {
+        args::SymlinkTarget::Reported => "original paths as reported",
+        args::SymlinkTarget::Canonical => "canonicalized original paths",
+        args::SymlinkTarget::Relative => "paths relative to each duplicate's directory",
+    }
+}
+
+/// The mechanism that actually replaced a duplicate. `reason` is only set when
+/// `--link-type auto` made the choice.
+struct PlacedLink {
+    link_type: args::LinkType,
+    reason: Option<String>,
+}
+
+/// Share the extents of `duplicate_path` with `original_path` in place and
+/// report how many bytes the kernel deduplicated or refused because they
+/// differ.
+fn dedupe_duplicate(
+    original_path: &std::path::Path,
+    duplicate_path: &std::path::Path,
+    errors: &std::sync::Mutex<Vec<Error>>,
+) -> ReplaceOutcome {
+    match clone::dedupe(original_path, duplicate_path) {
+        Ok(outcome) => {
+            let deduped = outcome.bytes(clone::RangeStatus::Deduped);
+            let differed = outcome.bytes(clone::RangeStatus::Differed);
+            let unsupported = outcome.bytes(clone::RangeStatus::Unsupported);
+
+            println!(
+                "Deduplicated '{}' against '{}': {} byte(s) shared, {} byte(s) differed, {} byte(s) unsupported",
+                duplicate_path.display(),
+                original_path.display(),
+                deduped,
+                differed,
+                unsupported
+            );
+            for range in &outcome.ranges {
+                println!(
+                    "  bytes {}..{}: {}",
+                    range.offset,
+                    range.offset + range.len,
+                    range.status.name()
+                );
+            }
+
+            if differed > 0 {
+                eprintln!(
+                    "Kernel refused to share {} byte(s) of '{}' that differ from '{}'",
+                    differed,
+                    duplicate_path.display(),
+                    original_path.display()
+                );
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Kernel refused to share {} byte(s) of '{}' that differ from '{}'",
+                        differed,
+                        duplicate_path.display(),
+                        original_path.display()
+                    ));
+            }
+
+            if unsupported > 0 {
+                eprintln!(
+                    "Failed to deduplicate '{}' against '{}': filesystem does not support extent dedupe for {} byte(s)",
+                    duplicate_path.display(),
+                    original_path.display(),
+                    unsupported
+                );
+                errors
+                    .lock()
+                    .expect("Should be able to unwrap lock")
+                    .push(anyhow::anyhow!(
+                        "Failed to deduplicate '{}' against '{}': filesystem does not support extent dedupe for {} byte(s)",
+                        duplicate_path.display(),
+                        original_path.display(),
+                        unsupported
+                    ));
+            }
+
+            ReplaceOutcome::Deduped {
+                shared_bytes: deduped,
+                complete: differed == 0 && unsupported == 0,
+            }
+        }
+        Err(e) => {
+            let reason = if matches!(
+                e.kind(),
+                std::io::ErrorKind::Unsupported | std::io::ErrorKind::InvalidInput
+            ) {
+                format!("filesystem does not support extent dedupe ({})", e)
+            } else {
+                e.to_string()
+            };
+            eprintln!(
+                "Failed to deduplicate '{}' against '{}': {}",
+                duplicate_path.display(),
+                original_path.display(),
+                reason
+            );
+            errors
+                .lock()
+                .expect("Should be able to unwrap lock")
+                .push(anyhow::anyhow!(
+                    "Failed to deduplicate '{}' against '{}': {}",
+                    duplicate_path.display(),
+                    original_path.display(),
+                    reason
+                ));
+            ReplaceOutcome::Failed
+        }
+    }
+}
+
+/// Build the link for `duplicate_path` under the new name `link_path`, sharing
+/// its directory. Every mechanism creates exclusively, so an existing
+/// `link_path` fails with `AlreadyExists` and is never touched.
+///
+/// `symlink_target` is what a symlink will contain; hard links and reflinks
+/// always use `original_path`. Reflinks are independent files, so they inherit
+/// the permissions of the duplicate rather than those of the original.
+fn create_link(
+    link_type: args::LinkType,
+    original_path: &std::path::Path,
+    symlink_target: &std::path::Path,
+    duplicate_path: &std::path::Path,
+    link_path: &std::path::Path,
+) -> Result<PlacedLink, std::io::Error> {
+    match link_type {
+        args::LinkType::Symlink => create_symlink(symlink_target, link_path)?,
+        args::LinkType::Hardlink => std::fs::hard_link(original_path, link_path)?,
+        args::LinkType::Reflink => {
+            let permissions = std::fs::metadata(duplicate_path)?.permissions();
+            clone::reflink(original_path, link_path)?;
+            if let Err(e) = std::fs::set_permissions(link_path, permissions) {
+                let _ = std::fs::remove_file(link_path);
+                return Err(e);
+            }
+        }
+        args::LinkType::Dedupe => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "extent dedupe does not create links",
+            ));
+        }
+        args::LinkType::Auto => {
+            return create_auto_link(original_path, symlink_target, duplicate_path, link_path);
+        }
+    }
+
+    Ok(PlacedLink {
+        link_type,
+        reason: None,
+    })
+}
+
+/// Try a reflink first, then a hard link when both files share a device, and
+/// finally fall back to a symlink, recording why each step was passed over.
+fn create_auto_link(
+    original_path: &std::path::Path,
+    symlink_target: &std::path::Path,
+    duplicate_path: &std::path::Path,
+    link_path: &std::path::Path,
+) -> Result<PlacedLink, std::io::Error> {
+    let reflink_err = match create_link(
+        args::LinkType::Reflink,
+        original_path,
+        symlink_target,
+        duplicate_path,
+        link_path,
+    ) {
+        Ok(_) => {
+            return Ok(PlacedLink {
+                link_type: args::LinkType::Reflink,
+                reason: Some("filesystem supports reflinks".to_string()),
+            });
+        }
+        Err(e) => e,
+    };
+
+    let hardlink_skipped = match same_device(original_path, duplicate_path) {
+        Ok(true) => match std::fs::hard_link(original_path, link_path) {
+            Ok(_) => {
+                return Ok(PlacedLink {
+                    link_type: args::LinkType::Hardlink,
+                    reason: Some(format!(
+                        "reflink unavailable ({}); both files are on the same device",
+                        reflink_err
+                    )),
+                });
+            }
+            Err(e) => format!("hard link failed ({})", e),
+        },
+        Ok(false) => "files are on different devices".to_string(),
+        Err(e) => format!("devices could not be compared ({})", e),
+    };
+
+    create_symlink(symlink_target, link_path)?;
+
+    Ok(PlacedLink {
+        link_type: args::LinkType::Symlink,
+        reason: Some(format!(
+            "reflink unavailable ({}); {}",
+            reflink_err, hardlink_skipped
+        )),
+    })
+}
+
+fn create_symlink(
+    symlink_target: &std::path::Path,
+    link_path: &std::path::Path,
+) -> Result<(), std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        std::os::unix::fs::symlink(symlink_target, link_path)
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        std::os::windows::fs::symlink_file(symlink_target, link_path)
+    }
+}
+
+/// Work out what a new symlink at `duplicate_path` should contain.
+///
+/// `canonical_original` is the path already resolved by the allow-root check;
+/// it is recomputed when absent.
+pub(crate) fn symlink_target(
+    mode: args::SymlinkTarget,
+    original_path: &std::path::Path,
+    canonical_original: Option<&std::path::Path>,
+    duplicate_path: &std::path::Path,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let canonical_original = || match canonical_original {
+        Some(path) => Ok(path.to_path_buf()),
+        None => std::fs::canonicalize(original_path),
+    };
+
+    match mode {
+        args::SymlinkTarget::Reported => {
+            // Windows resolves relative report paths against the process, so
+            // pin them down before they end up inside a link.
+            #[cfg(target_family = "windows")]
+            {
+                original_path.canonicalize()
+            }
+
+            #[cfg(target_family = "unix")]
+            {
+                Ok(original_path.to_path_buf())
+            }
+        }
+        args::SymlinkTarget::Canonical => canonical_original(),
+        args::SymlinkTarget::Relative => {
+            // The kernel resolves a relative link against the directory that
+            // physically holds it, so both ends are canonicalized first. That
+            // keeps symlinked parent directories from skewing the `..` count.
+            let duplicate_dir = match duplicate_path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => std::path::Path::new("."),
+            };
+            let base = std::fs::canonicalize(duplicate_dir)?;
+            let target = canonical_original()?;
+
+            relative_path(&base, &target).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "'{}' and '{}' share no common ancestor",
+                        base.display(),
+                        target.display()
+                    ),
+                )
+            })
+        }
+    }
+}
+
+/// Express the absolute path `target` relative to the absolute directory
+/// `base`, or `None` when they share no root (e.g. different Windows drives).
+fn relative_path(base: &std::path::Path, target: &std::path::Path) -> Option<std::path::PathBuf> {
+    let base = base.components().collect::<Vec<_>>();
+    let target = target.components().collect::<Vec<_>>();
+
+    let common = base.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    if common == 0 {
+        return None;
+    }
+
+    let mut relative = std::path::PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &target[common..] {
+        relative.push(component.as_os_str());
+    }
+
+    Some(relative)
+}
+
+/// Whether the original lives on the same device as the directory holding the
+/// duplicate, which is where the hard link will be created.
+fn same_device(
+    original_path: &std::path::Path,
+    duplicate_path: &std::path::Path,
+) -> Result<bool, std::io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let duplicate_dir = match duplicate_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+
+        Ok(std::fs::metadata(original_path)?.dev() == std::fs::metadata(duplicate_dir)?.dev())
+    }
+
+    // Stable Rust does not expose volume serial numbers on Windows; the OS
+    // rejects cross-volume hard links before the duplicate is swapped out.
+    #[cfg(target_family = "windows")]
+    {
+        let _ = (original_path, duplicate_path);
+        Ok(true)
+    }
+}
+
+/// Canonicalize `path` and confirm it lives under one of `allowed_roots`,
+/// returning the canonical form so callers do not resolve it twice.
+pub(crate) fn ensure_path_within_roots(
+    path: &str,
+    allowed_roots: &[std::path::PathBuf],
+) -> Result<std::path::PathBuf, Error> {
+    if allowed_roots.is_empty() {
+        anyhow::bail!("No allow-root paths configured.");
+    }
+
+    let canonical_path = std::fs::canonicalize(path)
+        .with_context(|| format!("Failed to canonicalize path '{}'.", path))?;
+
+    let is_allowed = allowed_roots
+        .iter()
+        .any(|root| canonical_path.starts_with(root));
+
+    if is_allowed {
+        Ok(canonical_path)
+    } else {
+        Err(outside_roots_error(path, allowed_roots))
+    }
+}
+
+fn outside_roots_error(path: &str, allowed_roots: &[std::path::PathBuf]) -> Error {
+    let roots = allowed_roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    anyhow::anyhow!(
+        "Path '{}' is outside the configured allow-root directories: {}",
+        path,
+        roots
+    )
+}
+
+pub(crate) fn choose_original_file<'a>(
     args: &args::Args,
     elms: &'a [CzkawkaDuplicateJsonFormatElement],
 ) -> &'a CzkawkaDuplicateJsonFormatElement {
@@ -445,10 +2357,13 @@ fn file_timestamp(entry: &CzkawkaDuplicateJsonFormatElement) -> i128 {
 type FileSizeKey = u64;
 type CzkawkaDuplicateJsonFormat = HashMap<FileSizeKey, Vec<Vec<CzkawkaDuplicateJsonFormatElement>>>;
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct CzkawkaDuplicateJsonFormatElement {
-    path: String,
-    modified_date: i64,
-    size: i64,
-    hash: String,
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub(crate) struct CzkawkaDuplicateJsonFormatElement {
+    pub(crate) path: String,
+    pub(crate) modified_date: i64,
+    pub(crate) size: i64,
+    pub(crate) hash: String,
+    /// Filled in once the path has passed the allow-root check.
+    #[serde(skip)]
+    pub(crate) canonical_path: Option<std::path::PathBuf>,
 }
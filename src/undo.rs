@@ -0,0 +1,478 @@
+//! Reverse a finished run: every link it created (and that still looks exactly
+//! as the run left it) is replaced by a real copy of the original's content.
+
+use crate::cancel::CancellationToken;
+use crate::journal::{self, CompletedReplacement, Journal};
+use crate::{args, directories, lock, rename};
+use anyhow::{Context, Error, Result};
+use std::path::{Path, PathBuf};
+
+/// Turn every link created by the finished run `args.run_id` back into a real
+/// copy of its original.
+///
+/// Each link is checked before it is touched: a symlink must still contain the
+/// recorded target, a hard link must still share the original's inode, and the
+/// original must still have the recorded size and BLAKE3 hash, checked on the
+/// copy (for a directory symlink, of the whole tree). Reflinks are already
+/// independent copies and are left as they are; they are counted separately
+/// and not recorded as undone. Results are printed per file; undone entries
+/// are recorded in the journal so a second `undo` skips them.
+///
+/// # Errors
+/// - the run has no journal in `state_dir`, or it never finished
+/// - another run holds a lock on an overlapping allow-root
+/// - a link or its original changed since the run, or lies outside
+///   `allow_roots`
+/// - the copy cannot be written
+///
+/// # Examples
+/// ```no_run
+/// use czkawka_dupe_to_symlinks::{UndoArgs, undo_run};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let args = UndoArgs {
+///     run_id: "1760000000-4242".into(),
+///     state_dir: Some("/var/lib/czkawka-dupes-to-symlinks".into()),
+///     allow_roots: vec!["/srv/media".into()],
+///     ..Default::default()
+/// };
+///
+/// undo_run(&args)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn undo_run(args: &args::UndoArgs) -> Result<(), Error> {
+    let Some(state_dir) = &args.state_dir else {
+        anyhow::bail!("Undo needs the state directory that holds the run journals.");
+    };
+
+    let replacements = match journal::completed_replacements(state_dir, &args.run_id) {
+        Ok(replacements) => replacements,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Err(e);
+        }
+    };
+
+    let (journal, _run_lock) = if args.dry_run {
+        (Journal::disabled(), None)
+    } else {
+        let run_lock = match lock::lock_dir(args.lock_dir.as_deref(), Some(state_dir)) {
+            Some(dir) => Some(lock::acquire(
+                &dir,
+                &args.allow_roots,
+                &format!("undo-{}", std::process::id()),
+                false,
+                &CancellationToken::new(),
+            )?),
+            None => None,
+        };
+        (Journal::reopen(state_dir, &args.run_id)?, run_lock)
+    };
+
+    let mut errors = Vec::new();
+    let (mut restored, mut left) = (0, 0);
+
+    for replacement in &replacements {
+        match undo_replacement(args, replacement) {
+            Ok(Undone::LeftAsIs(message)) => {
+                println!("{}", message);
+                left += 1;
+            }
+            Ok(Undone::Restored(message)) => {
+                println!("{}", message);
+                restored += 1;
+                if let Err(e) = journal.undone(replacement.entry) {
+                    errors.push(anyhow::anyhow!(
+                        "Restored '{}' but failed to record it in the journal: {}",
+                        replacement.duplicate.display(),
+                        e
+                    ));
+                }
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                errors.push(e);
+            }
+        }
+    }
+
+    println!(
+        "Undo of run {}: {} of {} link(s) restored, {} reflink(s) left as-is",
+        args.run_id,
+        restored,
+        replacements.len(),
+        left
+    );
+
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Encountered {} error(s):\n{}",
+            errors.len(),
+            errors
+                .iter()
+                .map(|e| format!("  - {:#}", e))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+/// What `undo` did with one link.
+enum Undone {
+    /// The link was replaced by a real copy (or would be, in a dry run).
+    Restored(String),
+    /// Nothing needed restoring; the entry stays recorded as a replacement.
+    LeftAsIs(String),
+}
+
+fn undo_replacement(
+    args: &args::UndoArgs,
+    replacement: &CompletedReplacement,
+) -> Result<Undone, Error> {
+    let duplicate = &replacement.duplicate;
+    let original = &replacement.original;
+
+    ensure_parent_within_roots(duplicate, &args.allow_roots)?;
+
+    match replacement.link_type.as_str() {
+        "symlink" | journal::DIRECTORY_SYMLINK => {
+            let current = std::fs::read_link(duplicate).ok();
+            if current.is_none() || current != replacement.link_target {
+                anyhow::bail!(
+                    "Skipped '{}': it is no longer the symlink this run created.",
+                    duplicate.display()
+                );
+            }
+        }
+        "hard link" => {
+            let current = journal::file_id(duplicate).ok().flatten();
+            if current.is_none() || current != replacement.original_id {
+                anyhow::bail!(
+                    "Skipped '{}': it is no longer a hard link to '{}'.",
+                    duplicate.display(),
+                    original.display()
+                );
+            }
+        }
+        "reflink" => {
+            return Ok(Undone::LeftAsIs(format!(
+                "Left '{}' as-is: a reflink is already an independent copy",
+                duplicate.display()
+            )));
+        }
+        other => anyhow::bail!(
+            "Skipped '{}': cannot undo a {} replacement.",
+            duplicate.display(),
+            other
+        ),
+    }
+
+    let (Some(size), Some(hash)) = (replacement.original_size, &replacement.original_blake3) else {
+        anyhow::bail!(
+            "Skipped '{}': the journal does not record the original's size and hash.",
+            duplicate.display()
+        );
+    };
+
+    if replacement.link_type == journal::DIRECTORY_SYMLINK {
+        if args.dry_run {
+            return Ok(Undone::Restored(format!(
+                "[Dry Run] Would restore directory '{}' as a real copy of '{}'",
+                duplicate.display(),
+                original.display()
+            )));
+        }
+
+        restore_tree_copy(original, duplicate, size, hash)?;
+
+        return Ok(Undone::Restored(format!(
+            "Restored directory '{}' as a real copy of '{}'",
+            duplicate.display(),
+            original.display()
+        )));
+    }
+
+    original_unchanged(replacement, duplicate, size)?;
+
+    if args.dry_run {
+        return Ok(Undone::Restored(format!(
+            "[Dry Run] Would restore '{}' as a real copy of '{}'",
+            duplicate.display(),
+            original.display()
+        )));
+    }
+
+    restore_copy(replacement, size, hash)?;
+
+    Ok(Undone::Restored(format!(
+        "Restored '{}' as a real copy of '{}'",
+        duplicate.display(),
+        original.display()
+    )))
+}
+
+/// Fail unless `original` still looks like the file the run linked to: the
+/// same identity, `size` and modification time as recorded. This only saves
+/// copying a file that obviously changed; the copy is checked against the
+/// recorded hash either way.
+fn original_unchanged(
+    replacement: &CompletedReplacement,
+    duplicate: &Path,
+    size: u64,
+) -> Result<(), Error> {
+    let original = &replacement.original;
+    let metadata = std::fs::metadata(original).with_context(|| {
+        format!(
+            "Skipped '{}': cannot read '{}'.",
+            duplicate.display(),
+            original.display()
+        )
+    })?;
+
+    if metadata.len() != size {
+        anyhow::bail!(
+            "Skipped '{}': '{}' is now {} bytes instead of {}.",
+            duplicate.display(),
+            original.display(),
+            metadata.len(),
+            size
+        );
+    }
+
+    let replaced = replacement.original_id.is_some()
+        && journal::file_id(original).ok().flatten() != replacement.original_id;
+    let modified = replacement.original_modified.is_some()
+        && metadata.modified().ok() != replacement.original_modified;
+
+    if replaced || modified {
+        anyhow::bail!(
+            "Skipped '{}': '{}' no longer has the content the run linked to.",
+            duplicate.display(),
+            original.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Copy the original next to `duplicate`, hashing on the way, and rename the
+/// copy over the link only if its content still matches `expected_hash`.
+fn restore_copy(
+    replacement: &CompletedReplacement,
+    size: u64,
+    expected_hash: &str,
+) -> Result<(), Error> {
+    let (original, duplicate) = (&replacement.original, &replacement.duplicate);
+    let mut source = std::fs::File::open(original)
+        .with_context(|| format!("Failed to open '{}'.", original.display()))?;
+    let permissions = source.metadata()?.permissions();
+
+    let (mut copy, copy_path) = create_copy(duplicate, |candidate| {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(candidate)
+    })?;
+
+    let written = (|| -> Result<(u64, String), std::io::Error> {
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut written = 0;
+        loop {
+            let read = std::io::Read::read(&mut source, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            std::io::Write::write_all(&mut copy, &buffer[..read])?;
+            written += read as u64;
+        }
+        copy.sync_all()?;
+        std::fs::set_permissions(&copy_path, permissions)?;
+        Ok((written, hasher.finalize().to_hex().to_string()))
+    })();
+
+    let result = match written {
+        Ok((written, hash)) if written == size && hash == expected_hash => {
+            std::fs::rename(&copy_path, duplicate)
+                .with_context(|| format!("Failed to move the copy over '{}'.", duplicate.display()))
+        }
+        Ok(_) => Err(anyhow::anyhow!(
+            "Skipped '{}': '{}' no longer has the content the run linked to.",
+            duplicate.display(),
+            original.display()
+        )),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to copy '{}' next to '{}'.",
+                original.display(),
+                duplicate.display()
+            )
+        }),
+    };
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&copy_path);
+    }
+
+    result
+}
+
+/// Copy the tree at `original` next to `duplicate` and swap it in for the
+/// directory symlink only if the copy still has the size and tree hash the run
+/// recorded.
+fn restore_tree_copy(
+    original: &Path,
+    duplicate: &Path,
+    expected_size: u64,
+    expected_hash: &str,
+) -> Result<(), Error> {
+    let ((), copy_path) = create_copy(duplicate, |candidate| std::fs::create_dir(candidate))?;
+
+    let result = (|| -> Result<(), Error> {
+        copy_tree(original, &copy_path).with_context(|| {
+            format!(
+                "Failed to copy '{}' next to '{}'.",
+                original.display(),
+                duplicate.display()
+            )
+        })?;
+
+        let (size, hash) = journal::tree_digest(&copy_path)
+            .with_context(|| format!("Failed to read the copy '{}'.", copy_path.display()))?;
+        if size != expected_size || hash != expected_hash {
+            anyhow::bail!(
+                "Skipped '{}': '{}' no longer has the content the run linked to.",
+                duplicate.display(),
+                original.display()
+            );
+        }
+
+        // A directory cannot be renamed over a link; without an exchange the
+        // link is removed first.
+        match rename::exchange(&copy_path, duplicate) {
+            Ok(()) => {
+                if let Err(e) = directories::remove_dir_symlink(&copy_path) {
+                    eprintln!(
+                        "Restored '{}' but failed to remove the old link, now at '{}': {}",
+                        duplicate.display(),
+                        copy_path.display(),
+                        e
+                    );
+                }
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                directories::remove_dir_symlink(duplicate)
+                    .and_then(|()| std::fs::rename(&copy_path, duplicate))
+                    .with_context(|| {
+                        format!("Failed to move the copy over '{}'.", duplicate.display())
+                    })
+            }
+            Err(e) => Err(e).with_context(|| {
+                format!("Failed to move the copy over '{}'.", duplicate.display())
+            }),
+        }
+    })();
+
+    if result.is_err() && std::fs::symlink_metadata(&copy_path).is_ok_and(|m| m.is_dir()) {
+        let _ = std::fs::remove_dir_all(&copy_path);
+    }
+
+    result
+}
+
+/// Recreate the files and directories of `from` inside the empty directory
+/// `to`, syncing each file. Directory permissions are applied last so
+/// read-only directories can still be filled.
+fn copy_tree(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    let mut permissions = vec![(to.to_path_buf(), std::fs::metadata(from)?.permissions())];
+
+    for entry in walkdir::WalkDir::new(from).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from).unwrap_or(entry.path());
+        let destination = to.join(relative);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            std::fs::create_dir(&destination)?;
+            permissions.push((destination, entry.metadata()?.permissions()));
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), &destination)?;
+            std::fs::File::open(&destination)?.sync_all()?;
+        } else {
+            return Err(std::io::Error::other(format!(
+                "'{}' is neither a file nor a directory",
+                entry.path().display()
+            )));
+        }
+    }
+
+    for (dir, permissions) in permissions.into_iter().rev() {
+        std::fs::set_permissions(&dir, permissions)?;
+    }
+
+    Ok(())
+}
+
+/// Create a fresh, hidden entry in the duplicate's directory to receive the
+/// copy with `create`, never reusing an existing name.
+fn create_copy<T>(
+    duplicate: &Path,
+    create: impl Fn(&Path) -> Result<T, std::io::Error>,
+) -> Result<(T, PathBuf), Error> {
+    let file_name = duplicate
+        .file_name()
+        .with_context(|| format!("'{}' has no file name.", duplicate.display()))?;
+    let parent = duplicate.parent().unwrap_or(Path::new(""));
+
+    let mut counter = 0u32;
+    loop {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(file_name);
+        name.push(format!(".czkawka-undo-{}-{}", std::process::id(), counter));
+        let candidate = parent.join(name);
+
+        match create(&candidate) {
+            Ok(created) => return Ok((created, candidate)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create '{}'.", candidate.display()));
+            }
+        }
+    }
+}
+
+/// The link itself is replaced, so only its directory has to be inside an
+/// allow-root; resolving the link would land on the original instead.
+pub(crate) fn ensure_parent_within_roots(
+    path: &Path,
+    allowed_roots: &[PathBuf],
+) -> Result<(), Error> {
+    if allowed_roots.is_empty() {
+        anyhow::bail!("No allow-root paths configured.");
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let canonical_parent = std::fs::canonicalize(parent)
+        .with_context(|| format!("Failed to canonicalize path '{}'.", parent.display()))?;
+
+    if !allowed_roots
+        .iter()
+        .any(|root| canonical_parent.starts_with(root))
+    {
+        anyhow::bail!(
+            "Skipped '{}': it is outside the configured allow-root directories.",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
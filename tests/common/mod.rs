@@ -0,0 +1,44 @@
+//! Fixtures shared by the integration tests. Each test binary uses a different
+//! subset, so unused helpers are expected.
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+pub fn write_json(path: &Path, entries: serde_json::Value) {
+    let mut file = File::create(path).expect("Failed to create json file");
+    file.write_all(entries.to_string().as_bytes())
+        .expect("Failed to write json");
+}
+
+pub fn canonicalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).expect("Failed to canonicalize path")
+}
+
+/// One report entry for a 4-byte file.
+pub fn report_entry(path: &Path, hash: &str) -> serde_json::Value {
+    json!({
+        "path": path.to_string_lossy(),
+        "modified_date": 0,
+        "size": 4,
+        "hash": hash
+    })
+}
+
+/// A report with one group of 4-byte `members` sharing `hash`.
+pub fn write_group_report(json_path: &Path, members: &[&Path], hash: &str) {
+    let entries = members
+        .iter()
+        .map(|path| report_entry(path, hash))
+        .collect::<Vec<_>>();
+
+    write_json(json_path, json!({ "4": [entries] }));
+}
+
+/// A report with one group of two 4-byte files, `original` listed first.
+pub fn write_pair_report(json_path: &Path, original: &Path, duplicate: &Path) {
+    write_group_report(json_path, &[original, duplicate], "hash123");
+}
@@ -0,0 +1,1033 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+
+use czkawka_dupe_to_symlinks::{
+    Args, CancellationToken, OriginalToKeep, ResumeJournal, UndoArgs,
+    replace_duplicates_with_symlinks, undo_run, validate_files,
+};
+use serde_json::json;
+use tempfile::TempDir;
+
+use common::{canonicalize, report_entry, write_json, write_pair_report};
+
+fn file_id(path: &std::path::Path) -> serde_json::Value {
+    let metadata = fs::symlink_metadata(path).expect("metadata");
+    json!({ "dev": metadata.dev(), "ino": metadata.ino() })
+}
+
+/// Write the journal run `run_id` would leave behind if it was killed after
+/// staging the link for `duplicate` at `staged_link`. With `link_id` the link
+/// was created and recorded; without it the run died while creating it.
+fn write_interrupted_journal(
+    state_dir: &std::path::Path,
+    run_id: &str,
+    duplicate: &std::path::Path,
+    original: &std::path::Path,
+    staged_link: &std::path::Path,
+    duplicate_id: serde_json::Value,
+    link_id: Option<serde_json::Value>,
+) {
+    let journals = state_dir.join("journals");
+    fs::create_dir_all(&journals).expect("create journals");
+
+    let mut records = vec![
+        json!({
+            "op": "begin",
+            "run_id": run_id,
+            "pid": u32::MAX,
+            "started": 1,
+            "roots": [duplicate.parent().expect("duplicate directory")],
+            "lock_dir": state_dir.join("locks")
+        }),
+        json!({
+            "op": "stage",
+            "entry": 0,
+            "duplicate": duplicate,
+            "original": original,
+            "link_type": "symlink",
+            "staged_link": staged_link,
+            "duplicate_id": duplicate_id,
+            "link_target": original,
+            "original_id": file_id(original),
+            "original_size": fs::metadata(original).expect("original").len()
+        }),
+    ];
+    if let Some(link_id) = link_id {
+        records.push(json!({
+            "op": "linked",
+            "entry": 0,
+            "link_type": "symlink",
+            "link_target": original,
+            "link_id": link_id
+        }));
+    }
+    let lines = records
+        .iter()
+        .map(|record| format!("{}\n", record))
+        .collect::<String>();
+    // The torn last line of a killed process must be ignored.
+    fs::write(
+        journals.join(format!("{}.jsonl", run_id)),
+        lines + "{\"op\":\"comm",
+    )
+    .expect("write journal");
+}
+
+fn unrelated_pair(
+    root: &std::path::Path,
+) -> (std::path::PathBuf, std::path::PathBuf, std::path::PathBuf) {
+    let original = root.join("other-original.bin");
+    let duplicate = root.join("other-dup.bin");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&duplicate, b"same").expect("write duplicate");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+    (original, duplicate, json_path)
+}
+
+#[test]
+fn journaled_run_closes_its_journal() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("journaled run");
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+
+    let journals = fs::read_dir(state_dir.join("journals").join("done"))
+        .expect("journals")
+        .filter_map(Result::ok)
+        .map(|entry| fs::read_to_string(entry.path()).expect("read journal"))
+        .collect::<Vec<String>>();
+    assert_eq!(journals.len(), 1);
+
+    let ops = journals[0]
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("record")["op"].clone())
+        .collect::<Vec<serde_json::Value>>();
+    assert_eq!(ops, ["begin", "stage", "linked", "commit", "done", "end"]);
+    // Closed journals are moved aside, so later runs do not replay them.
+    assert!(
+        !fs::read_dir(state_dir.join("journals"))
+            .expect("journals")
+            .filter_map(Result::ok)
+            .any(|entry| entry.path().extension().is_some_and(|e| e == "jsonl"))
+    );
+}
+
+#[test]
+fn interrupted_swap_blocks_the_next_run_until_rolled_back() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+
+    // The killed run had already exchanged the link into place: the duplicate
+    // path is the link and the backup name holds the old file.
+    let original = root.join("original.bin");
+    let duplicate = root.join("dup.bin");
+    let backup = root.join("dup.czkawka-bak");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&backup, b"same").expect("write old duplicate");
+    std::os::unix::fs::symlink(&original, &duplicate).expect("link");
+    write_interrupted_journal(
+        &state_dir,
+        "1-crashed",
+        &duplicate,
+        &original,
+        &backup,
+        file_id(&backup),
+        Some(file_id(&duplicate)),
+    );
+
+    let (other_original, other_duplicate, json_path) = unrelated_pair(&root);
+    let mut args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("unfinished journal should block the run")
+        .to_string();
+    assert!(
+        err.contains("--resume-journal"),
+        "unexpected error: {}",
+        err
+    );
+    assert!(
+        fs::symlink_metadata(&other_duplicate)
+            .expect("other")
+            .is_file()
+    );
+
+    args.resume_journal = Some(ResumeJournal::Rollback);
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("rollback and run");
+
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+    assert!(!backup.exists());
+    assert_eq!(
+        fs::read_link(&other_duplicate).expect("other link"),
+        other_original
+    );
+
+    // The recovered journal is closed, so the next run starts normally.
+    args.resume_journal = None;
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("later run");
+}
+
+#[test]
+fn finish_keeps_the_backup_when_the_original_is_gone() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+
+    // The link was swapped in, then the original was deleted before resuming:
+    // the backup is the last copy of the data.
+    let original = root.join("original.bin");
+    let duplicate = root.join("dup.bin");
+    let backup = root.join("dup.czkawka-bak");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&backup, b"same").expect("write old duplicate");
+    std::os::unix::fs::symlink(&original, &duplicate).expect("link");
+    write_interrupted_journal(
+        &state_dir,
+        "1-crashed",
+        &duplicate,
+        &original,
+        &backup,
+        file_id(&backup),
+        Some(file_id(&duplicate)),
+    );
+    fs::remove_file(&original).expect("remove original");
+
+    let (_, _, json_path) = unrelated_pair(&root);
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir),
+        resume_journal: Some(ResumeJournal::Finish),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("finish must not delete the last copy");
+    assert!(format!("{:#}", err).contains("no longer leads"), "{err:#}");
+    assert_eq!(fs::read(&backup).expect("backup kept"), b"same");
+}
+
+#[test]
+fn finish_moves_a_staged_link_into_place() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+
+    // The killed run had built the link but not swapped it in yet.
+    let original = root.join("original.bin");
+    let duplicate = root.join("dup.bin");
+    let staged = root.join("dup.czkawka-bak");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&duplicate, b"same").expect("write duplicate");
+    std::os::unix::fs::symlink(&original, &staged).expect("staged link");
+    write_interrupted_journal(
+        &state_dir,
+        "1-crashed",
+        &duplicate,
+        &original,
+        &staged,
+        file_id(&duplicate),
+        Some(file_id(&staged)),
+    );
+
+    let (_, _, json_path) = unrelated_pair(&root);
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir),
+        resume_journal: Some(ResumeJournal::Finish),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("finish and run");
+
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+    assert!(!staged.exists());
+}
+
+#[test]
+fn resume_accepts_a_stage_whose_link_never_appeared() {
+    for link_created in [false, true] {
+        let temp = TempDir::new().expect("tempdir");
+        let root = canonicalize(temp.path());
+        let state_dir = root.join("state");
+
+        // The killed run had journaled the staged name but not the link: it
+        // died before, or while, creating it.
+        let original = root.join("original.bin");
+        let duplicate = root.join("dup.bin");
+        let staged = root.join("dup.czkawka-bak");
+        fs::write(&original, b"same").expect("write original");
+        fs::write(&duplicate, b"same").expect("write duplicate");
+        if link_created {
+            std::os::unix::fs::symlink(&original, &staged).expect("staged link");
+        }
+        write_interrupted_journal(
+            &state_dir,
+            "1-crashed",
+            &duplicate,
+            &original,
+            &staged,
+            file_id(&duplicate),
+            None,
+        );
+
+        let (other_original, other_duplicate, json_path) = unrelated_pair(&root);
+        let args = Args {
+            input_file_path: json_path.to_string_lossy().into_owned(),
+            original_to_keep: OriginalToKeep::First,
+            allow_roots: vec![root.clone()],
+            state_dir: Some(state_dir),
+            resume_journal: Some(ResumeJournal::Finish),
+            ..Default::default()
+        };
+
+        let files = validate_files(&args.input_file_path).expect("validate");
+        replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+            .expect("resume and run");
+
+        assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+        assert!(fs::symlink_metadata(&staged).is_err(), "leftover link kept");
+        assert_eq!(
+            fs::read_link(&other_duplicate).expect("other link"),
+            other_original
+        );
+    }
+}
+
+#[test]
+fn undo_restores_real_copies_once() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("journaled run");
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_symlink());
+
+    let journal = fs::read_dir(state_dir.join("journals").join("done"))
+        .expect("journals")
+        .next()
+        .expect("one journal")
+        .expect("journal entry")
+        .path();
+    let run_id = journal
+        .file_stem()
+        .expect("run id")
+        .to_string_lossy()
+        .into_owned();
+
+    let undo = UndoArgs {
+        run_id,
+        state_dir: Some(state_dir.clone()),
+        allow_roots: vec![root.clone()],
+        ..Default::default()
+    };
+    undo_run(&undo).expect("undo");
+
+    let metadata = fs::symlink_metadata(&duplicate).expect("dup");
+    assert!(metadata.is_file());
+    assert_ne!(metadata.ino(), fs::metadata(&original).expect("orig").ino());
+    assert_eq!(fs::read(&duplicate).expect("read dup"), b"same");
+
+    // The journal records the undo, so running it again leaves the copy alone.
+    fs::write(&duplicate, b"edited").expect("edit copy");
+    undo_run(&undo).expect("second undo");
+    assert_eq!(fs::read(&duplicate).expect("read dup"), b"edited");
+}
+
+#[test]
+fn undo_skips_links_changed_since_the_run() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("journaled run");
+
+    let run_id = fs::read_dir(state_dir.join("journals").join("done"))
+        .expect("journals")
+        .next()
+        .expect("one journal")
+        .expect("journal entry")
+        .path()
+        .file_stem()
+        .expect("run id")
+        .to_string_lossy()
+        .into_owned();
+
+    let modified = fs::metadata(&original)
+        .expect("original")
+        .modified()
+        .expect("mtime");
+    fs::write(&original, b"diff").expect("rewrite original");
+
+    let undo = UndoArgs {
+        run_id,
+        state_dir: Some(state_dir),
+        allow_roots: vec![root],
+        ..Default::default()
+    };
+    assert!(undo_run(&undo).is_err());
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_symlink());
+
+    // Same size and a restored modification time still do not pass: the copy
+    // is checked against the hash recorded at link time.
+    fs::write(&original, b"evil").expect("rewrite original in place");
+    File::options()
+        .write(true)
+        .open(&original)
+        .and_then(|file| file.set_modified(modified))
+        .expect("restore mtime");
+    assert!(undo_run(&undo).is_err());
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_symlink());
+}
+
+#[test]
+fn undo_leaves_reflinks_as_is_without_recording_them_as_undone() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let journals = state_dir.join("journals");
+    fs::create_dir_all(&journals).expect("create journals");
+
+    let original = root.join("original.bin");
+    let duplicate = root.join("dup.bin");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&duplicate, b"same").expect("write reflinked duplicate");
+
+    let records = [
+        json!({ "op": "begin", "run_id": "1-reflinked", "pid": u32::MAX, "started": 1, "roots": [root], "lock_dir": state_dir.join("locks") }),
+        json!({
+            "op": "stage",
+            "entry": 0,
+            "duplicate": duplicate,
+            "original": original,
+            "link_type": "reflink",
+            "staged_link": root.join("dup.czkawka-bak"),
+            "duplicate_id": file_id(&duplicate)
+        }),
+        json!({ "op": "commit", "entry": 0 }),
+        json!({ "op": "done", "entry": 0 }),
+        json!({ "op": "end" }),
+    ];
+    let journal = journals.join("1-reflinked.jsonl");
+    fs::write(
+        &journal,
+        records
+            .iter()
+            .map(|record| format!("{}\n", record))
+            .collect::<String>(),
+    )
+    .expect("write journal");
+
+    let undo = UndoArgs {
+        run_id: "1-reflinked".into(),
+        state_dir: Some(state_dir),
+        allow_roots: vec![root],
+        ..Default::default()
+    };
+    undo_run(&undo).expect("undo");
+
+    assert_eq!(fs::read(&duplicate).expect("read dup"), b"same");
+    let journal = fs::read_to_string(&journal).expect("read journal");
+    assert!(!journal.contains("undone"), "{journal}");
+}
+
+/// Hold a lock in `locks` like a running process would, covering `root`.
+fn hold_run_lock(locks: &std::path::Path, root: &std::path::Path, run_id: &str) -> File {
+    fs::create_dir_all(locks).expect("create locks");
+    let mut file = File::create(locks.join(format!("{}.lock", run_id))).expect("lock file");
+    file.lock().expect("take lock");
+    let uid = file.metadata().expect("lock metadata").uid();
+    file.write_all(
+        json!({ "run_id": run_id, "pid": 4242, "uid": uid, "started": 1_760_000_000, "roots": [root] })
+            .to_string()
+            .as_bytes(),
+    )
+    .expect("write lock");
+    file
+}
+
+#[test]
+fn overlapping_run_is_refused_until_the_lock_is_free() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    // A stale lock from a run that died must not get in the way.
+    let locks = state_dir.join("locks");
+    fs::create_dir_all(&locks).expect("create locks");
+    fs::write(locks.join("1-dead.lock"), b"{}").expect("stale lock");
+
+    // The holder locked the parent of this run's allow-root.
+    let holder = hold_run_lock(&locks, root.parent().expect("parent"), "1-holder");
+
+    let mut args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("overlapping run must be refused");
+    let message = format!("{:#}", err);
+    assert!(message.contains("PID 4242"), "{message}");
+    assert!(message.contains("2025-10-09 08:53:20 UTC"), "{message}");
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+    assert!(!locks.join("1-dead.lock").exists());
+
+    args.wait = true;
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        drop(holder);
+    });
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("run after waiting");
+    release.join().expect("release thread");
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+    // The released holder's file is cleared like any other stale lock.
+    assert!(!locks.join("1-holder.lock").exists());
+}
+
+#[test]
+fn resume_leaves_entries_outside_the_allow_roots_to_their_own_tree() {
+    let first_disk = TempDir::new().expect("first disk");
+    let second_disk = TempDir::new().expect("second disk");
+    let first_root = canonicalize(first_disk.path());
+    let second_root = canonicalize(second_disk.path());
+    let state_dir = first_root.join("state");
+
+    // The killed run had swapped in the link under the second root.
+    let original = second_root.join("original.bin");
+    let duplicate = second_root.join("dup.bin");
+    let backup = second_root.join("dup.czkawka-bak");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&backup, b"same").expect("write old duplicate");
+    std::os::unix::fs::symlink(&original, &duplicate).expect("link");
+    write_interrupted_journal(
+        &state_dir,
+        "1-crashed",
+        &duplicate,
+        &original,
+        &backup,
+        file_id(&backup),
+        Some(file_id(&duplicate)),
+    );
+    let journal = state_dir.join("journals").join("1-crashed.jsonl");
+
+    // A run over the first root neither touches it nor closes its journal.
+    let (_, _, json_path) = unrelated_pair(&first_root);
+    let mut args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![first_root.clone()],
+        state_dir: Some(state_dir.clone()),
+        resume_journal: Some(ResumeJournal::Rollback),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("run over the first root");
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_symlink());
+    assert!(backup.exists());
+    assert!(
+        !fs::read_to_string(&journal)
+            .expect("journal")
+            .contains("\"end\"")
+    );
+
+    // A run over the second root needs the interrupted run's roots, which a
+    // live run holds.
+    let (_, _, json_path) = unrelated_pair(&second_root);
+    args.input_file_path = json_path.to_string_lossy().into_owned();
+    args.allow_roots = vec![second_root.clone()];
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let holder = hold_run_lock(&state_dir.join("locks"), &second_root, "1-holder");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("held roots should block recovery")
+        .to_string();
+    assert!(err.contains("1-holder"), "unexpected error: {}", err);
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_symlink());
+
+    drop(holder);
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("rollback over the second root");
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+    assert!(!backup.exists());
+    assert!(!journal.exists());
+    assert!(
+        fs::read_to_string(
+            state_dir
+                .join("journals")
+                .join("done")
+                .join("1-crashed.jsonl")
+        )
+        .expect("closed journal")
+        .contains("\"end\"")
+    );
+}
+
+#[test]
+fn journal_of_a_run_that_still_holds_its_lock_is_left_open() {
+    let temp = TempDir::new().expect("tempdir");
+    let other_tree = TempDir::new().expect("other tree");
+    let root = canonicalize(temp.path());
+    let other_root = canonicalize(other_tree.path());
+    let state_dir = root.join("state");
+
+    // A live run over another tree, between two replacements, that locked in
+    // its own lock dir rather than this run's.
+    let other_locks = root.join("other-locks");
+    let journals = state_dir.join("journals");
+    fs::create_dir_all(&journals).expect("create journals");
+    let journal = journals.join("1-live.jsonl");
+    fs::write(
+        &journal,
+        format!(
+            "{}\n",
+            json!({ "op": "begin", "run_id": "1-live", "pid": u32::MAX, "started": 1, "roots": [other_root], "lock_dir": other_locks })
+        ),
+    )
+    .expect("write journal");
+    let holder = hold_run_lock(&other_locks, &other_root, "1-live");
+
+    let (_, _, json_path) = unrelated_pair(&root);
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("run");
+    assert!(
+        !fs::read_to_string(&journal)
+            .expect("journal")
+            .contains("\"end\"")
+    );
+
+    // Once its lock is free the run is known to be gone and its journal is closed.
+    drop(holder);
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("later run");
+    assert!(!journal.exists());
+    assert!(
+        fs::read_to_string(journals.join("done").join("1-live.jsonl"))
+            .expect("closed journal")
+            .contains("\"end\"")
+    );
+}
+
+#[test]
+fn resume_run_applies_each_action_to_the_named_run_only() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let original = root.join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+
+    // The first run had swapped its link in; the second had only staged one.
+    let swapped = root.join("swapped.bin");
+    let swapped_backup = root.join("swapped.czkawka-bak");
+    fs::write(&swapped_backup, b"same").expect("write old duplicate");
+    std::os::unix::fs::symlink(&original, &swapped).expect("link");
+    write_interrupted_journal(
+        &state_dir,
+        "1-crashed",
+        &swapped,
+        &original,
+        &swapped_backup,
+        file_id(&swapped_backup),
+        Some(file_id(&swapped)),
+    );
+
+    let staged = root.join("staged.bin");
+    let staged_link = root.join("staged.czkawka-bak");
+    fs::write(&staged, b"same").expect("write duplicate");
+    std::os::unix::fs::symlink(&original, &staged_link).expect("staged link");
+    write_interrupted_journal(
+        &state_dir,
+        "2-crashed",
+        &staged,
+        &original,
+        &staged_link,
+        file_id(&staged),
+        Some(file_id(&staged_link)),
+    );
+
+    let (_, _, json_path) = unrelated_pair(&root);
+    let mut args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+
+    // The refusal names every entry so each run can get its own action.
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("unfinished runs should block the run")
+        .to_string();
+    for expected in ["1-crashed", "2-crashed", "swapped.bin", "staged.bin"] {
+        assert!(err.contains(expected), "missing {}: {}", expected, err);
+    }
+
+    args.resume_journal = Some(ResumeJournal::Finish);
+    args.resume_runs = vec!["2-crashed".into()];
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("the run left out should still block")
+        .to_string();
+    assert!(err.contains("1-crashed"), "unexpected error: {}", err);
+    assert!(!err.contains("2-crashed"), "unexpected error: {}", err);
+    assert_eq!(fs::read_link(&staged).expect("finished link"), original);
+    assert!(
+        fs::symlink_metadata(&swapped)
+            .expect("swapped")
+            .is_symlink()
+    );
+
+    args.resume_journal = Some(ResumeJournal::Rollback);
+    args.resume_runs = vec!["1-crashed".into()];
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("roll back the other run");
+    assert!(fs::symlink_metadata(&swapped).expect("swapped").is_file());
+    assert!(!swapped_backup.exists());
+    assert_eq!(fs::read_link(&staged).expect("finished link"), original);
+}
+
+/// Two copies of one album under `root` and a report pairing their files.
+fn album_pair(
+    root: &std::path::Path,
+) -> (std::path::PathBuf, std::path::PathBuf, std::path::PathBuf) {
+    let kept = root.join("alice").join("album");
+    let copy = root.join("bob").join("album");
+    for dir in [&kept, &copy] {
+        fs::create_dir_all(dir.join("disc2")).expect("create album");
+        fs::write(dir.join("track1.flac"), b"one!").expect("write track1");
+        fs::write(dir.join("disc2").join("track2.flac"), b"two!").expect("write track2");
+    }
+    // Keeps bob's folder as a whole from matching alice's.
+    fs::write(root.join("bob").join("notes.txt"), b"mine").expect("write notes");
+
+    let member =
+        |dir: &std::path::Path, relative: &str, hash: &str| report_entry(&dir.join(relative), hash);
+    let json_path = root.join("input.json");
+    write_json(
+        &json_path,
+        json!({ "4": [
+            [member(&kept, "track1.flac", "hash0001"), member(&copy, "track1.flac", "hash0001")],
+            [member(&kept, "disc2/track2.flac", "hash0002"), member(&copy, "disc2/track2.flac", "hash0002")]
+        ] }),
+    );
+    (kept, copy, json_path)
+}
+
+#[test]
+fn undo_restores_a_replaced_directory() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let state_dir = root.join("state");
+    let (kept, copy, json_path) = album_pair(&root);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(state_dir.clone()),
+        dedupe_directories: true,
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("directory run");
+    assert_eq!(fs::read_link(&copy).expect("directory link"), kept);
+
+    let journal = fs::read_dir(state_dir.join("journals").join("done"))
+        .expect("journals")
+        .next()
+        .expect("one journal")
+        .expect("journal entry")
+        .path();
+    let contents = fs::read_to_string(&journal).expect("read journal");
+    assert!(contents.contains("\"directory symlink\""), "{contents}");
+
+    let undo = UndoArgs {
+        run_id: journal
+            .file_stem()
+            .expect("run id")
+            .to_string_lossy()
+            .into_owned(),
+        state_dir: Some(state_dir),
+        allow_roots: vec![root.clone()],
+        ..Default::default()
+    };
+    undo_run(&undo).expect("undo");
+
+    let metadata = fs::symlink_metadata(&copy).expect("copy");
+    assert!(metadata.is_dir());
+    assert_eq!(
+        fs::read(copy.join("disc2").join("track2.flac")).expect("restored track"),
+        b"two!"
+    );
+    assert_ne!(
+        fs::metadata(copy.join("track1.flac"))
+            .expect("restored")
+            .ino(),
+        fs::metadata(kept.join("track1.flac")).expect("kept").ino()
+    );
+    assert!(fs::symlink_metadata(&kept).expect("kept").is_dir());
+    assert_eq!(
+        fs::read_dir(root.join("bob")).expect("bob").count(),
+        2,
+        "undo left a copy or link behind"
+    );
+}
+
+#[test]
+fn resume_resolves_an_interrupted_directory_swap() {
+    // The killed run had either exchanged the link in (the old tree waits under
+    // the staged name) or moved the tree to its backup name without an
+    // exchange, and not yet moved the link in.
+    for (exchanged, action) in [
+        (true, ResumeJournal::Rollback),
+        (true, ResumeJournal::Finish),
+        (false, ResumeJournal::Rollback),
+        (false, ResumeJournal::Finish),
+    ] {
+        let temp = TempDir::new().expect("tempdir");
+        let root = canonicalize(temp.path());
+        let state_dir = root.join("state");
+        let (kept, copy, _) = album_pair(&root);
+        let staged = root.join("bob").join("album.czkawka-bak");
+        let backup = root.join("bob").join("album.czkawka-bak-1");
+
+        let old_tree = if exchanged { &staged } else { &backup };
+        fs::rename(&copy, old_tree).expect("move tree aside");
+        let old_id = file_id(old_tree);
+        let link_at = if exchanged { &copy } else { &staged };
+        std::os::unix::fs::symlink(&kept, link_at).expect("link");
+        let link_id = file_id(link_at);
+
+        let mut records = vec![
+            json!({ "op": "begin", "run_id": "1-crashed", "pid": u32::MAX, "started": 1, "roots": [root], "lock_dir": state_dir.join("locks") }),
+            json!({
+                "op": "stage",
+                "entry": 0,
+                "duplicate": copy,
+                "original": kept,
+                "link_type": "directory symlink",
+                "staged_link": staged,
+                "duplicate_id": old_id,
+                "link_target": kept
+            }),
+            json!({
+                "op": "linked",
+                "entry": 0,
+                "link_type": "directory symlink",
+                "link_target": kept,
+                "link_id": link_id
+            }),
+        ];
+        if !exchanged {
+            records.push(json!({ "op": "backup", "entry": 0, "backup": backup }));
+        }
+        let journals = state_dir.join("journals");
+        fs::create_dir_all(&journals).expect("create journals");
+        fs::write(
+            journals.join("1-crashed.jsonl"),
+            records
+                .iter()
+                .map(|record| format!("{}\n", record))
+                .collect::<String>(),
+        )
+        .expect("write journal");
+
+        let (_, _, json_path) = unrelated_pair(&root);
+        let args = Args {
+            input_file_path: json_path.to_string_lossy().into_owned(),
+            original_to_keep: OriginalToKeep::First,
+            allow_roots: vec![root.clone()],
+            state_dir: Some(state_dir),
+            resume_journal: Some(action),
+            ..Default::default()
+        };
+        let files = validate_files(&args.input_file_path).expect("validate");
+        replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+            .expect("resume and run");
+
+        if action == ResumeJournal::Finish {
+            assert_eq!(fs::read_link(&copy).expect("directory link"), kept);
+        } else {
+            assert!(fs::symlink_metadata(&copy).expect("copy").is_dir());
+            assert_eq!(fs::read(copy.join("track1.flac")).expect("track"), b"one!");
+        }
+        assert!(
+            fs::symlink_metadata(&staged).is_err(),
+            "staged name left behind"
+        );
+        assert!(fs::symlink_metadata(&backup).is_err(), "backup left behind");
+    }
+}
+
+#[test]
+fn runs_with_their_own_state_dirs_share_the_lock_dir() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let shared_locks = root.join("shared-locks");
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    // Another user's run: its own state dir, the same lock dir.
+    let holder = hold_run_lock(&shared_locks, &root, "1-other-user");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(root.join("my-state")),
+        lock_dir: Some(shared_locks.clone()),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("the shared lock should block the run")
+        .to_string();
+    assert!(err.contains("1-other-user"), "unexpected error: {}", err);
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+    assert!(!root.join("my-state").join("locks").exists());
+
+    drop(holder);
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("run");
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+}
+
+#[test]
+fn symlinked_lock_dir_is_refused_and_its_target_left_alone() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let (_original, duplicate, json_path) = unrelated_pair(&root);
+
+    // An unheld lock file in the directory the symlink points at.
+    let target = root.join("elsewhere");
+    fs::create_dir(&target).expect("create target");
+    fs::write(target.join("system.lock"), b"").expect("foreign lock");
+    let locks = root.join("locks");
+    std::os::unix::fs::symlink(&target, &locks).expect("symlink lock dir");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(root.join("state")),
+        lock_dir: Some(locks),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("a symlinked lock dir must be refused");
+    assert!(
+        format!("{:#}", err).contains("symlinks are refused"),
+        "{err:#}"
+    );
+    assert!(target.join("system.lock").exists());
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+}
+
+#[test]
+fn sticky_lock_dir_of_another_user_is_shared_and_forged_locks_ignored() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let (original, duplicate, json_path) = unrelated_pair(&root);
+
+    // Another user created the lock dir; only root can set that up here.
+    let locks = root.join("locks");
+    fs::create_dir(&locks).expect("create locks");
+    if std::os::unix::fs::chown(&locks, Some(65534), Some(65534)).is_err() {
+        return;
+    }
+    fs::set_permissions(&locks, fs::Permissions::from_mode(0o755)).expect("chmod");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(root.join("state")),
+        lock_dir: Some(locks.clone()),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("a private lock dir of another user must be refused");
+    assert!(format!("{:#}", err).contains("UID 65534"), "{err:#}");
+
+    // World-writable and sticky like /tmp, it is shared. A held lock owned by
+    // one user but claiming another's UID was forged and does not block.
+    fs::set_permissions(&locks, fs::Permissions::from_mode(0o1777)).expect("chmod");
+    let forged = hold_run_lock(&locks, &root, "1-forged");
+    std::os::unix::fs::chown(locks.join("1-forged.lock"), Some(65534), None).expect("chown");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("run");
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+    assert!(locks.join("1-forged.lock").exists());
+    drop(forged);
+}
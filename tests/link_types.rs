@@ -0,0 +1,395 @@
+mod common;
+
+use std::fs;
+
+use czkawka_dupe_to_symlinks::{
+    Args, CancellationToken, LinkType, OriginalToKeep, SymlinkTarget,
+    replace_duplicates_with_symlinks, shares_extents, validate_files,
+};
+use tempfile::TempDir;
+
+use common::{canonicalize, write_pair_report};
+
+#[test]
+#[cfg(unix)]
+fn hardlink_mode_replaces_duplicate_with_hard_link() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path();
+
+    let original = root.join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, b"same").expect("write dup");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        link_type: LinkType::Hardlink,
+        allow_roots: vec![canonicalize(root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("hard link run");
+
+    let duplicate_meta = fs::symlink_metadata(&duplicate).expect("dup metadata");
+    assert!(
+        duplicate_meta.file_type().is_file(),
+        "Expected a regular file"
+    );
+    assert_eq!(
+        duplicate_meta.ino(),
+        fs::metadata(&original).expect("original metadata").ino()
+    );
+    assert_eq!(duplicate_meta.nlink(), 2);
+    assert!(!root.join("dup.czkawka-bak").exists());
+}
+
+/// Large enough to live in real extents rather than inline in the inode
+/// (btrfs inlines small files, and inline data is never shared).
+fn extent_sized_content() -> Vec<u8> {
+    (0..128 * 1024).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+#[cfg(unix)]
+fn reflink_mode_clones_or_leaves_duplicate_untouched() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path();
+    let content = extent_sized_content();
+
+    let original = root.join("original.bin");
+    fs::write(&original, &content).expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, &content).expect("write dup");
+    let duplicate_ino = fs::metadata(&duplicate).expect("dup metadata").ino();
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        link_type: LinkType::Reflink,
+        allow_roots: vec![canonicalize(root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
+
+    // Either way the duplicate stays an independent regular file with its
+    // original content.
+    let duplicate_meta = fs::symlink_metadata(&duplicate).expect("dup metadata");
+    assert!(
+        duplicate_meta.file_type().is_file(),
+        "Expected a regular file"
+    );
+    assert_eq!(fs::read(&duplicate).expect("read dup"), content);
+    assert!(!root.join("dup.czkawka-bak").exists());
+
+    match result {
+        Ok(()) => {
+            assert_ne!(duplicate_meta.ino(), duplicate_ino, "Expected a new clone");
+            // Linux reports the shared extents; other systems cannot be asked.
+            if cfg!(target_os = "linux") {
+                assert!(shares_extents(&original, &duplicate).expect("fiemap"));
+            }
+            fs::write(&duplicate, b"diff").expect("write clone");
+            assert_eq!(fs::read(&original).expect("read original"), content);
+        }
+        Err(e) => {
+            let err = format!("{}", e);
+            assert!(
+                err.contains("Failed to create reflink"),
+                "Unexpected error message: {}",
+                err
+            );
+            // Cloning failed under the staging name, so nothing was exchanged.
+            assert_eq!(duplicate_meta.ino(), duplicate_ino);
+            assert!(!shares_extents(&original, &duplicate).unwrap_or(false));
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn dedupe_mode_never_stages_or_replaces_duplicate() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path();
+    let content = extent_sized_content();
+
+    let original = root.join("original.bin");
+    fs::write(&original, &content).expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, &content).expect("write dup");
+    let duplicate_ino = fs::metadata(&duplicate).expect("dup metadata").ino();
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        link_type: LinkType::Dedupe,
+        allow_roots: vec![canonicalize(root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
+
+    // Dedupe works in place: same file, same bytes, no backup.
+    let duplicate_meta = fs::symlink_metadata(&duplicate).expect("dup metadata");
+    assert!(
+        duplicate_meta.file_type().is_file(),
+        "Expected a regular file"
+    );
+    assert_eq!(duplicate_meta.ino(), duplicate_ino);
+    assert_eq!(fs::read(&duplicate).expect("read dup"), content);
+    assert!(!root.join("dup.czkawka-bak").exists());
+
+    match result {
+        Ok(()) => assert!(shares_extents(&original, &duplicate).expect("fiemap")),
+        Err(e) => {
+            let err = format!("{}", e);
+            assert!(
+                err.contains("Failed to deduplicate"),
+                "Unexpected error message: {}",
+                err
+            );
+            assert!(!shares_extents(&original, &duplicate).unwrap_or(false));
+        }
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn dedupe_mode_prints_each_range_and_totals() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+
+    let original = root.join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, b"same").expect("write dup");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_czkawka-dupes-to-symlinks"))
+        .arg("--input-file-path")
+        .arg(&json_path)
+        .args(["--original-to-keep", "first", "--link-type", "dedupe"])
+        .arg("--allow-root")
+        .arg(&root)
+        .arg("--state-dir")
+        .arg(root.join("state"))
+        .arg("--lock-dir")
+        .arg(root.join("locks"))
+        .output()
+        .expect("run cli");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let summary = format!(
+        "Deduplicated '{}' against '{}': ",
+        duplicate.display(),
+        original.display()
+    );
+
+    // tmpfs and ext4 refuse FIDEDUPERANGE; btrfs and XFS share the range.
+    if stdout.contains("  bytes 0..4: unsupported") {
+        assert!(
+            stdout.contains(&format!(
+                "{summary}0 byte(s) shared, 0 byte(s) differed, 4 byte(s) unsupported"
+            )),
+            "unexpected output: {}",
+            stdout
+        );
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr)
+                .contains("filesystem does not support extent dedupe for 4 byte(s)")
+        );
+    } else {
+        assert!(
+            stdout.contains(&format!(
+                "{summary}4 byte(s) shared, 0 byte(s) differed, 0 byte(s) unsupported"
+            )),
+            "unexpected output: {}",
+            stdout
+        );
+        assert!(stdout.contains("  bytes 0..4: shared"), "{}", stdout);
+        assert!(output.status.success());
+    }
+    assert_eq!(fs::read(&duplicate).expect("read dup"), b"same");
+}
+
+#[test]
+#[cfg(unix)]
+fn auto_mode_keeps_real_file_on_same_device() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path();
+
+    let original = root.join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, b"same").expect("write dup");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        link_type: LinkType::Auto,
+        allow_roots: vec![canonicalize(root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("auto run");
+
+    // Same device, so auto must settle on a reflink or a hard link; both leave
+    // a regular file behind rather than a symlink.
+    let duplicate_meta = fs::symlink_metadata(&duplicate).expect("dup metadata");
+    assert!(
+        duplicate_meta.file_type().is_file(),
+        "Expected a regular file"
+    );
+    assert_eq!(fs::read(&duplicate).expect("read dup"), b"same");
+    assert!(!root.join("dup.czkawka-bak").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn relative_symlinks_survive_moving_the_tree() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path().join("tree");
+    fs::create_dir_all(root.join("a")).expect("create a");
+    fs::create_dir_all(root.join("real")).expect("create real");
+    std::os::unix::fs::symlink(root.join("real"), root.join("alias")).expect("alias dir");
+
+    let original = root.join("a").join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(root.join("real").join("dup.bin"), b"same").expect("write dup");
+    // Report the duplicate through the symlinked parent directory.
+    let duplicate = root.join("alias").join("dup.bin");
+
+    let json_path = temp.path().join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        symlink_target: SymlinkTarget::Relative,
+        allow_roots: vec![canonicalize(&root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("relative run");
+
+    let link = root.join("real").join("dup.bin");
+    assert_eq!(
+        fs::read_link(&link).expect("read link"),
+        std::path::Path::new("../a/original.bin")
+    );
+
+    let moved = temp.path().join("moved");
+    fs::rename(&root, &moved).expect("move tree");
+    assert_eq!(
+        fs::read(moved.join("real").join("dup.bin")).expect("read through link"),
+        b"same"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn canonical_symlinks_skip_symlinked_directories() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = temp.path();
+    fs::create_dir_all(root.join("real")).expect("create real");
+    std::os::unix::fs::symlink(root.join("real"), root.join("mount")).expect("mount alias");
+
+    fs::write(root.join("real").join("original.bin"), b"same").expect("write original");
+    // Report the original through the symlinked "mount point".
+    let original = root.join("mount").join("original.bin");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, b"same").expect("write dup");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        symlink_target: SymlinkTarget::Canonical,
+        allow_roots: vec![canonicalize(root)],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("canonical run");
+
+    assert_eq!(
+        fs::read_link(&duplicate).expect("read link"),
+        canonicalize(&root.join("real").join("original.bin"))
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn rerunning_a_report_leaves_linked_duplicates_alone() {
+    use std::os::unix::fs::MetadataExt;
+
+    for (link_type, name) in [
+        (LinkType::Symlink, "symlink"),
+        (LinkType::Hardlink, "hard link"),
+    ] {
+        let temp = TempDir::new().expect("tempdir");
+        let root = canonicalize(temp.path());
+
+        let original = root.join("original.bin");
+        let duplicate = root.join("dup.bin");
+        fs::write(&original, b"same").expect("write original");
+        fs::write(&duplicate, b"same").expect("write dup");
+
+        let json_path = root.join("input.json");
+        write_pair_report(&json_path, &original, &duplicate);
+
+        let args = Args {
+            input_file_path: json_path.to_string_lossy().into_owned(),
+            original_to_keep: OriginalToKeep::First,
+            link_type,
+            allow_roots: vec![root.clone()],
+            state_dir: Some(root.join("state")),
+            ..Default::default()
+        };
+        let files = validate_files(&args.input_file_path).expect("validate files");
+
+        replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+            .expect("first run");
+        let linked = fs::symlink_metadata(&duplicate).expect("dup metadata");
+
+        replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+            .expect("second run");
+        let relinked = fs::symlink_metadata(&duplicate).expect("dup metadata");
+
+        // The second run neither staged nor recreated the link.
+        assert_eq!(linked.ino(), relinked.ino(), "{name}");
+        assert_eq!(linked.ctime_nsec(), relinked.ctime_nsec(), "{name}");
+        assert!(!root.join("dup.czkawka-bak").exists());
+    }
+}
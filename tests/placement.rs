@@ -0,0 +1,304 @@
+mod common;
+
+use std::fs;
+
+use czkawka_dupe_to_symlinks::{
+    Args, CancellationToken, OriginalToKeep, SpreadBy, replace_duplicates_with_symlinks,
+    validate_files,
+};
+use serde_json::json;
+use tempfile::TempDir;
+
+use common::{canonicalize, report_entry, write_group_report, write_json};
+
+/// Where `--vault` stores `content`: `<vault>/blake3/ab/cd/<hash>`.
+fn vault_object(vault: &std::path::Path, content: &[u8]) -> std::path::PathBuf {
+    let hash = blake3::hash(content).to_hex().to_string();
+    vault
+        .join("blake3")
+        .join(&hash[0..2])
+        .join(&hash[2..4])
+        .join(hash)
+}
+
+#[test]
+#[cfg(unix)]
+fn vault_mode_links_every_member_to_one_object() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let vault = root.join("vault");
+    let object = vault_object(&vault, b"same");
+
+    let first = root.join("alice.bin");
+    let second = root.join("bob.bin");
+    fs::write(&first, b"same").expect("write first");
+    fs::write(&second, b"same").expect("write second");
+
+    let json_path = root.join("input.json");
+    write_group_report(&json_path, &[&first, &second], "abcdef0123");
+
+    let mut args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        vault: Some(vault.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("vault run");
+
+    assert!(fs::symlink_metadata(&object).expect("object").is_file());
+    assert_eq!(fs::read_link(&first).expect("first link"), object);
+    assert_eq!(fs::read_link(&second).expect("second link"), object);
+
+    // A later report with the same hash reuses the existing object.
+    let third = root.join("carol.bin");
+    let fourth = root.join("dave.bin");
+    fs::write(&third, b"same").expect("write third");
+    fs::write(&fourth, b"same").expect("write fourth");
+    let later_json = root.join("later.json");
+    write_group_report(&later_json, &[&third, &fourth], "abcdef0123");
+    args.input_file_path = later_json.to_string_lossy().into_owned();
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("second vault run");
+
+    assert_eq!(fs::read_link(&third).expect("third link"), object);
+    assert_eq!(fs::read_link(&fourth).expect("fourth link"), object);
+    assert_eq!(fs::read(&object).expect("object content"), b"same");
+
+    // Re-applying the first report finds every member already linked.
+    args.input_file_path = json_path.to_string_lossy().into_owned();
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("repeated vault run");
+    assert_eq!(fs::read_link(&first).expect("first link"), object);
+    assert_eq!(fs::read_link(&second).expect("second link"), object);
+}
+
+#[test]
+#[cfg(unix)]
+fn vault_object_with_other_content_is_never_reused() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let vault = root.join("vault");
+    let object = vault_object(&vault, b"same");
+    fs::create_dir_all(object.parent().expect("object dir")).expect("create object dir");
+    // Named for the group's content but holding other bytes of the same size.
+    fs::write(&object, b"diff").expect("write object");
+
+    let first = root.join("alice.bin");
+    let second = root.join("bob.bin");
+    fs::write(&first, b"same").expect("write first");
+    fs::write(&second, b"same").expect("write second");
+
+    let json_path = root.join("input.json");
+    write_group_report(&json_path, &[&first, &second], "abcdef0123");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        vault: Some(vault.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    let err = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect_err("a mismatching vault object must fail the group");
+    assert!(err.to_string().contains("different content"), "{err}");
+
+    for path in [&first, &second] {
+        assert!(fs::symlink_metadata(path).expect("member").is_file());
+        assert_eq!(fs::read(path).expect("member content"), b"same");
+    }
+    assert_eq!(fs::read(&object).expect("object content"), b"diff");
+}
+
+#[test]
+#[cfg(unix)]
+fn vault_objects_are_named_by_content_not_by_report_hash() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let vault = root.join("vault");
+
+    // A stale BLAKE3 hint naming an object with other bytes is not reused.
+    let stale = vault_object(&vault, b"diff");
+    fs::create_dir_all(stale.parent().expect("object dir")).expect("create object dir");
+    fs::write(&stale, b"diff").expect("write stale object");
+    let stale_hash = blake3::hash(b"diff").to_hex().to_string();
+
+    let first = root.join("alice.bin");
+    let second = root.join("bob.bin");
+    let third = root.join("carol.bin");
+    let fourth = root.join("dave.bin");
+    fs::write(&first, b"same").expect("write first");
+    fs::write(&second, b"same").expect("write second");
+    fs::write(&third, b"othr").expect("write third");
+    fs::write(&fourth, b"othr").expect("write fourth");
+
+    // Two groups whose report hashes collide.
+    let json_path = root.join("input.json");
+    write_json(
+        &json_path,
+        json!({
+            "4": [
+                [report_entry(&first, &stale_hash), report_entry(&second, &stale_hash)],
+                [report_entry(&third, "abcdef0123"), report_entry(&fourth, "abcdef0123")]
+            ]
+        }),
+    );
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        vault: Some(vault.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("vault run");
+
+    let same = vault_object(&vault, b"same");
+    let other = vault_object(&vault, b"othr");
+    assert_eq!(fs::read_link(&first).expect("first link"), same);
+    assert_eq!(fs::read_link(&second).expect("second link"), same);
+    assert_eq!(fs::read_link(&third).expect("third link"), other);
+    assert_eq!(fs::read_link(&fourth).expect("fourth link"), other);
+    assert_eq!(fs::read(&same).expect("same object"), b"same");
+    assert_eq!(fs::read(&other).expect("other object"), b"othr");
+    assert_eq!(fs::read(&stale).expect("stale object"), b"diff");
+}
+
+#[test]
+#[cfg(unix)]
+fn canonical_dir_relocates_original_under_library() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let library = root.join("library");
+    fs::create_dir_all(root.join("users").join("bob")).expect("create bob");
+    fs::create_dir_all(root.join("users").join("alice")).expect("create alice");
+
+    let bob = root.join("users").join("bob").join("song.flac");
+    let alice = root.join("users").join("alice").join("song.flac");
+    fs::write(&bob, b"same").expect("write bob");
+    fs::write(&alice, b"same").expect("write alice");
+
+    let json_path = root.join("input.json");
+    write_group_report(&json_path, &[&bob, &alice], "abcdef0123");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        canonical_dir: Some(library.clone()),
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("canonical-dir run");
+
+    let relocated = library.join("users").join("bob").join("song.flac");
+    assert!(
+        fs::symlink_metadata(&relocated)
+            .expect("relocated")
+            .is_file()
+    );
+    assert_eq!(fs::read_link(&bob).expect("bob link"), relocated);
+    assert_eq!(fs::read_link(&alice).expect("alice link"), relocated);
+    assert_eq!(fs::read(&alice).expect("read through link"), b"same");
+}
+
+#[test]
+#[cfg(unix)]
+fn keep_copies_spreads_real_files_across_roots() {
+    let first_disk = TempDir::new().expect("first disk");
+    let second_disk = TempDir::new().expect("second disk");
+    let first_root = canonicalize(first_disk.path());
+    let second_root = canonicalize(second_disk.path());
+
+    let a1 = first_root.join("a1.bin");
+    let a2 = first_root.join("a2.bin");
+    let b1 = second_root.join("b1.bin");
+    let b2 = second_root.join("b2.bin");
+    for path in [&a1, &a2, &b1, &b2] {
+        fs::write(path, b"same").expect("write member");
+    }
+
+    let json_path = first_root.join("input.json");
+    write_group_report(&json_path, &[&a1, &a2, &b1, &b2], "abcdef0123");
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![first_root.clone(), second_root.clone()],
+        keep_copies: Some(2),
+        spread_by: SpreadBy::Root,
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("keep-copies run");
+
+    assert!(fs::symlink_metadata(&a1).expect("a1").is_file());
+    assert!(fs::symlink_metadata(&b1).expect("b1").is_file());
+    assert_eq!(fs::read_link(&a2).expect("a2 link"), a1);
+    assert_eq!(fs::read_link(&b2).expect("b2 link"), b1);
+}
+
+#[test]
+#[cfg(unix)]
+fn dedupe_directories_replaces_matching_tree_with_one_symlink() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let kept = root.join("alice").join("album");
+    let copy = root.join("bob").join("album");
+
+    for dir in [&kept, &copy] {
+        fs::create_dir_all(dir.join("disc2")).expect("create album");
+        fs::write(dir.join("track1.flac"), b"one!").expect("write track1");
+        fs::write(dir.join("disc2").join("track2.flac"), b"two!").expect("write track2");
+    }
+    // Keeps bob's folder as a whole from matching alice's.
+    fs::write(root.join("bob").join("notes.txt"), b"mine").expect("write notes");
+
+    let member =
+        |dir: &std::path::Path, relative: &str, hash: &str| report_entry(&dir.join(relative), hash);
+    let json_path = root.join("input.json");
+    write_json(
+        &json_path,
+        json!({ "4": [
+            [member(&kept, "track1.flac", "hash0001"), member(&copy, "track1.flac", "hash0001")],
+            [member(&kept, "disc2/track2.flac", "hash0002"), member(&copy, "disc2/track2.flac", "hash0002")]
+        ] }),
+    );
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        dedupe_directories: true,
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate files");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("directory run");
+
+    assert_eq!(fs::read_link(&copy).expect("directory link"), kept);
+    assert!(
+        fs::symlink_metadata(kept.join("track1.flac"))
+            .expect("kept")
+            .is_file()
+    );
+    assert_eq!(
+        fs::read(copy.join("disc2").join("track2.flac")).expect("read through link"),
+        b"two!"
+    );
+    assert!(!root.join("bob").join("album.czkawka-bak").exists());
+}
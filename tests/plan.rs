@@ -0,0 +1,210 @@
+mod common;
+
+use std::fs;
+
+use czkawka_dupe_to_symlinks::{
+    Args, CancellationToken, ExistingSymlinks, OriginalToKeep, replace_duplicates_with_symlinks,
+    validate_files,
+};
+use tempfile::TempDir;
+
+use common::{canonicalize, write_group_report};
+
+fn run_reports(root: &std::path::Path, reports: &std::path::Path) -> anyhow::Result<()> {
+    let args = Args {
+        input_file_path: reports.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.to_path_buf()],
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+}
+
+#[test]
+fn overlapping_reports_merge_into_one_group() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let data = root.join("data");
+    let reports = root.join("reports");
+    fs::create_dir(&data).expect("data dir");
+    fs::create_dir(&reports).expect("reports dir");
+
+    let first = data.join("a.bin");
+    let shared = data.join("b.bin");
+    let last = data.join("c.bin");
+    for path in [&first, &shared, &last] {
+        fs::write(path, b"same").expect("write member");
+    }
+
+    // The shared path is also listed twice, once through a `..` detour.
+    let detour = data.join("..").join("data").join("b.bin");
+    write_group_report(&reports.join("1.json"), &[&first, &shared], "abcdef0123");
+    write_group_report(
+        &reports.join("2.json"),
+        &[&shared, &detour, &last],
+        "abcdef0123",
+    );
+
+    run_reports(&root, &reports).expect("merged run");
+
+    assert!(fs::symlink_metadata(&first).expect("first").is_file());
+    for path in [&shared, &last] {
+        assert_eq!(fs::read_link(path).expect("link"), first);
+    }
+}
+
+#[test]
+fn groups_sharing_a_path_with_different_hashes_are_rejected() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let data = root.join("data");
+    let reports = root.join("reports");
+    fs::create_dir(&data).expect("data dir");
+    fs::create_dir(&reports).expect("reports dir");
+
+    let first = data.join("a.bin");
+    let shared = data.join("b.bin");
+    let last = data.join("c.bin");
+    fs::write(&first, b"same").expect("write first");
+    fs::write(&shared, b"same").expect("write shared");
+    fs::write(&last, b"diff").expect("write last");
+
+    // An unrelated group in the same run is still processed.
+    let other = data.join("d.bin");
+    let other_copy = data.join("e.bin");
+    fs::write(&other, b"othr").expect("write other");
+    fs::write(&other_copy, b"othr").expect("write other copy");
+
+    write_group_report(&reports.join("1.json"), &[&first, &shared], "abcdef0123");
+    write_group_report(&reports.join("2.json"), &[&shared, &last], "9876543210");
+    write_group_report(
+        &reports.join("3.json"),
+        &[&other, &other_copy],
+        "0123456789",
+    );
+
+    let err = run_reports(&root, &reports).expect_err("conflict must be reported");
+    assert!(err.to_string().contains("Conflicting"), "{err}");
+
+    for path in [&first, &shared, &last] {
+        assert!(fs::symlink_metadata(path).expect("member").is_file());
+    }
+    assert_eq!(fs::read_link(&other_copy).expect("other link"), other);
+}
+
+#[test]
+#[cfg(unix)]
+fn links_never_point_at_links_across_reports() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let data = root.join("data");
+    let reports = root.join("reports");
+    fs::create_dir(&data).expect("data dir");
+    fs::create_dir(&reports).expect("reports dir");
+
+    let original = data.join("a.bin");
+    let copy = data.join("b.bin");
+    let link = data.join("link.bin");
+    let other_copy = data.join("c.bin");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&copy, b"same").expect("write copy");
+    fs::write(&other_copy, b"same").expect("write other copy");
+    std::os::unix::fs::symlink(&original, &link).expect("symlink");
+
+    // The second report would otherwise keep the symlink as its original.
+    write_group_report(&reports.join("1.json"), &[&original, &copy], "abcdef0123");
+    write_group_report(&reports.join("2.json"), &[&link, &other_copy], "abcdef0123");
+
+    run_reports(&root, &reports).expect("planned run");
+
+    assert!(fs::symlink_metadata(&original).expect("original").is_file());
+    for path in [&copy, &link, &other_copy] {
+        assert_eq!(fs::read_link(path).expect("link"), original);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn symlink_cycles_are_reported_and_left_alone() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let data = root.join("data");
+    let reports = root.join("reports");
+    fs::create_dir(&data).expect("data dir");
+    fs::create_dir(&reports).expect("reports dir");
+
+    let first = data.join("loop-a.bin");
+    let second = data.join("loop-b.bin");
+    let copy = data.join("copy.bin");
+    std::os::unix::fs::symlink(&second, &first).expect("symlink a");
+    std::os::unix::fs::symlink(&first, &second).expect("symlink b");
+    fs::write(&copy, b"same").expect("write copy");
+
+    write_group_report(&reports.join("1.json"), &[&first, &copy], "abcdef0123");
+
+    let err = run_reports(&root, &reports).expect_err("cycle must be reported");
+    assert!(err.to_string().contains("Symlink cycle"), "{err}");
+    assert!(fs::symlink_metadata(&copy).expect("copy").is_file());
+    assert_eq!(fs::read_link(&first).expect("loop a"), second);
+}
+
+#[test]
+#[cfg(unix)]
+fn existing_symlinks_policy_decides_links_to_files_outside_the_group() {
+    for policy in [
+        ExistingSymlinks::Skip,
+        ExistingSymlinks::Resolve,
+        ExistingSymlinks::Error,
+    ] {
+        let temp = TempDir::new().expect("tempdir");
+        let root = canonicalize(temp.path());
+        let data = root.join("data");
+        fs::create_dir(&data).expect("data dir");
+
+        let outside = data.join("outside.bin");
+        let link = data.join("link.bin");
+        let first = data.join("a.bin");
+        let second = data.join("b.bin");
+        fs::write(&outside, b"same").expect("write outside");
+        std::os::unix::fs::symlink(&outside, &link).expect("symlink");
+        fs::write(&first, b"same").expect("write first");
+        fs::write(&second, b"same").expect("write second");
+
+        let json_path = root.join("input.json");
+        write_group_report(&json_path, &[&link, &first, &second], "abcdef0123");
+
+        let args = Args {
+            input_file_path: json_path.to_string_lossy().into_owned(),
+            original_to_keep: OriginalToKeep::First,
+            existing_symlinks: policy,
+            allow_roots: vec![root.clone()],
+            ..Default::default()
+        };
+        let files = validate_files(&args.input_file_path).expect("validate");
+        let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
+
+        assert_eq!(fs::read_link(&link).expect("link"), outside);
+        assert!(fs::symlink_metadata(&outside).expect("outside").is_file());
+
+        match policy {
+            ExistingSymlinks::Skip => {
+                result.expect("skip run");
+                assert!(fs::symlink_metadata(&first).expect("first").is_file());
+                assert_eq!(fs::read_link(&second).expect("second"), first);
+            }
+            ExistingSymlinks::Resolve => {
+                // The link's target joins the group ahead of it and is kept.
+                result.expect("resolve run");
+                assert_eq!(fs::read_link(&first).expect("first"), outside);
+                assert_eq!(fs::read_link(&second).expect("second"), outside);
+            }
+            ExistingSymlinks::Error => {
+                let err = result.expect_err("error policy must fail the group");
+                assert!(err.to_string().contains("not in its group"), "{err}");
+                assert!(fs::symlink_metadata(&first).expect("first").is_file());
+                assert!(fs::symlink_metadata(&second).expect("second").is_file());
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use czkawka_dupe_to_symlinks::{RecoverAction, RecoverArgs, recover_backups};
+use tempfile::TempDir;
+
+use common::canonicalize;
+
+fn recover(root: &std::path::Path, action: RecoverAction, dry_run: bool) -> anyhow::Result<()> {
+    recover_backups(&RecoverArgs {
+        roots: vec![root.to_path_buf()],
+        action,
+        dry_run,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn restore_puts_backups_back_over_links_and_missing_paths() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let original = root.join("original.jpg");
+    fs::write(&original, b"same").expect("write original");
+
+    // A suffix backup whose duplicate is already a symlink.
+    let linked = root.join("photo.jpg");
+    let linked_backup = root.join("photo.czkawka-bak");
+    fs::write(&linked_backup, b"same").expect("write backup");
+    symlink(&original, &linked).expect("symlink");
+
+    // A hidden backup whose duplicate path is gone.
+    let missing = root.join("clip.mp4");
+    let missing_backup = root.join(".clip.mp4.czkawka-bak.1760000000-42");
+    fs::write(&missing_backup, b"clip").expect("write backup");
+
+    recover(&root, RecoverAction::Restore, true).expect("dry run");
+    assert!(linked_backup.exists());
+    assert!(missing_backup.exists());
+
+    recover(&root, RecoverAction::Restore, false).expect("restore");
+    assert!(fs::symlink_metadata(&linked).expect("photo").is_file());
+    assert_eq!(fs::read(&linked).expect("read photo"), b"same");
+    assert_eq!(fs::read(&missing).expect("read clip"), b"clip");
+    assert!(!linked_backup.exists());
+    assert!(!missing_backup.exists());
+    assert_eq!(fs::read(&original).expect("read original"), b"same");
+}
+
+#[test]
+fn delete_and_restore_refuse_to_lose_the_last_copy() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let staging = root.join(".czkawka-staging");
+    fs::create_dir(&staging).expect("staging dir");
+
+    // The duplicate path is gone, so the backup is the only copy.
+    let orphan = staging.join("notes.txt.1760000000-42");
+    fs::write(&orphan, b"only").expect("write backup");
+
+    // The duplicate path holds a real file, so restoring would overwrite it.
+    let kept = root.join("kept.txt");
+    fs::write(&kept, b"new!").expect("write kept");
+    let kept_backup = staging.join("kept.txt.1760000000-42-1");
+    fs::write(&kept_backup, b"old!").expect("write backup");
+
+    // Same size, different bytes: the backup holds the only copy of `old!`.
+    assert!(recover(&root, RecoverAction::Delete, false).is_err());
+    assert_eq!(fs::read(&orphan).expect("read orphan"), b"only");
+    assert_eq!(fs::read(&kept_backup).expect("read backup"), b"old!");
+
+    assert!(recover(&root, RecoverAction::Restore, false).is_err());
+    assert_eq!(fs::read(&kept).expect("read kept"), b"new!");
+    assert_eq!(fs::read(root.join("notes.txt")).expect("notes"), b"only");
+    assert!(kept_backup.exists());
+}
+
+#[test]
+fn ambiguous_suffix_backups_are_left_alone() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    fs::write(root.join("song.mp3"), b"mp3!").expect("write mp3");
+    fs::write(root.join("song.flac"), b"flac").expect("write flac");
+    let backup = root.join("song.czkawka-bak-2");
+    fs::write(&backup, b"mp3!").expect("write backup");
+
+    recover(&root, RecoverAction::Leave, false).expect("listing");
+    assert!(recover(&root, RecoverAction::Delete, false).is_err());
+    assert!(backup.exists());
+}
+
+#[test]
+fn delete_removes_only_backups_whose_content_remains() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+
+    // An unrelated file of the same size sits at the original name.
+    let photo = root.join("photo.jpg");
+    let photo_backup = root.join("photo.czkawka-bak");
+    fs::write(&photo, b"BBBB").expect("write photo");
+    fs::write(&photo_backup, b"AAAA").expect("write backup");
+
+    // A directory backup next to an empty directory of the same name.
+    let album = root.join("album");
+    let staging = album.join(".czkawka-staging");
+    let tree_backup = staging.join("tree.1700000000-1");
+    fs::create_dir_all(album.join("tree")).expect("create empty tree");
+    fs::create_dir_all(&tree_backup).expect("create backup tree");
+    fs::write(tree_backup.join("only.txt"), b"only").expect("write tree file");
+
+    assert!(recover(&root, RecoverAction::Delete, false).is_err());
+    assert_eq!(fs::read(&photo_backup).expect("read backup"), b"AAAA");
+    assert_eq!(
+        fs::read(tree_backup.join("only.txt")).expect("read tree file"),
+        b"only"
+    );
+
+    // Once the original paths hold the same content, the backups can go.
+    fs::write(&photo, b"AAAA").expect("rewrite photo");
+    fs::write(album.join("tree").join("only.txt"), b"only").expect("fill tree");
+    recover(&root, RecoverAction::Delete, false).expect("delete");
+    assert!(!photo_backup.exists());
+    assert!(!tree_backup.exists());
+    assert_eq!(fs::read(&photo).expect("read photo"), b"AAAA");
+}
+
+#[test]
+fn restore_guesses_names_only_over_links_to_the_same_content() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let other = root.join("other.png");
+    fs::write(&other, b"PNG!").expect("write other");
+
+    // `photo.jpg` is gone; the only same-stem sibling links elsewhere.
+    let sibling = root.join("photo.png");
+    symlink(&other, &sibling).expect("symlink");
+    let backup = root.join("photo.czkawka-bak");
+    fs::write(&backup, b"JPG!").expect("write backup");
+
+    assert!(recover(&root, RecoverAction::Restore, false).is_err());
+    assert!(
+        fs::symlink_metadata(&sibling)
+            .expect("photo.png")
+            .is_symlink()
+    );
+    assert_eq!(fs::read(&backup).expect("read backup"), b"JPG!");
+    assert_eq!(fs::read(&other).expect("read other"), b"PNG!");
+    recover(&root, RecoverAction::Leave, false).expect("listing");
+}
@@ -1,21 +1,15 @@
-use std::fs::{self, File};
-use std::io::Write;
+mod common;
+
+use std::fs;
 
 use czkawka_dupe_to_symlinks::{
-    Args, OriginalToKeep, replace_duplicates_with_symlinks, validate_files,
+    Args, CancellationToken, OriginalToKeep, Staging, replace_duplicates_with_symlinks,
+    validate_files,
 };
 use serde_json::json;
 use tempfile::TempDir;
 
-fn write_json(path: &std::path::Path, entries: serde_json::Value) {
-    let mut file = File::create(path).expect("Failed to create json file");
-    file.write_all(entries.to_string().as_bytes())
-        .expect("Failed to write json");
-}
-
-fn canonicalize(path: &std::path::Path) -> std::path::PathBuf {
-    std::fs::canonicalize(path).expect("Failed to canonicalize path")
-}
+use common::{canonicalize, report_entry, write_group_report, write_json, write_pair_report};
 
 // Windows permission semantics do not reliably prevent the replace_duplicates_with_symlinks
 // operation when directory permissions are changed, causing this test to be non-deterministic
@@ -61,6 +55,7 @@ fn fails_when_duplicate_folder_cannot_be_staged() {
         dry_run: false,
         original_to_keep: OriginalToKeep::First,
         allow_roots: vec![canonicalize(root)],
+        ..Default::default()
     };
 
     let files = validate_files(&args.input_file_path).expect("validate files");
@@ -82,7 +77,7 @@ fn fails_when_duplicate_folder_cannot_be_staged() {
         fs::set_permissions(&data_dir, perms).expect("set perms");
     }
 
-    let result = replace_duplicates_with_symlinks(&args, &files);
+    let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
     assert!(result.is_err(), "Expected symlink run to fail");
 
     // Restore permissions so TempDir cleanup succeeds.
@@ -136,10 +131,11 @@ fn errors_when_path_outside_allow_root() {
         dry_run: false,
         original_to_keep: OriginalToKeep::First,
         allow_roots: vec![canonicalize(allowed.path())],
+        ..Default::default()
     };
 
     let files = validate_files(&args.input_file_path).expect("validate");
-    let result = replace_duplicates_with_symlinks(&args, &files);
+    let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
     assert!(result.is_err(), "Expected allow-root violation");
     let err = format!("{}", result.unwrap_err());
     assert!(
@@ -148,3 +144,329 @@ fn errors_when_path_outside_allow_root() {
         err
     );
 }
+
+#[test]
+#[cfg(unix)]
+fn swaps_link_in_without_touching_existing_backup_names() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+
+    let original = root.join("original.bin");
+    fs::write(&original, b"same").expect("write original");
+    let duplicate = root.join("dup.bin");
+    fs::write(&duplicate, b"same").expect("write dup");
+
+    // A leftover from an earlier run must survive; the new backup takes the
+    // next free name and is removed once the link is in place.
+    let leftover = root.join("dup.czkawka-bak");
+    fs::write(&leftover, b"keep me").expect("write leftover");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("replace");
+
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+    assert_eq!(fs::read(&leftover).expect("leftover"), b"keep me");
+    assert!(!root.join("dup.czkawka-bak-1").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn parallel_replacements_of_same_stem_files_keep_their_own_backups() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let originals = root.join("originals");
+    let shared = root.join("shared");
+    fs::create_dir(&originals).expect("create originals");
+    fs::create_dir(&shared).expect("create shared");
+
+    // Every duplicate is `shared/song.<ext>`, so all of them compete for the
+    // same `song.czkawka-bak[-N]` names while rayon processes groups in
+    // parallel. Distinct contents make any mix-up visible.
+    let mut groups = Vec::new();
+    let mut expected = Vec::new();
+    for index in 0..64 {
+        let content = format!("{:04}", index);
+        let original = originals.join(format!("song{}.flac", index));
+        let duplicate = shared.join(format!("song.e{}", index));
+        fs::write(&original, &content).expect("write original");
+        fs::write(&duplicate, &content).expect("write duplicate");
+
+        let hash = format!("hash{:04}", index);
+        groups.push(json!([
+            report_entry(&original, &hash),
+            report_entry(&duplicate, &hash)
+        ]));
+        expected.push((original, duplicate, content));
+    }
+
+    let json_path = root.join("input.json");
+    write_json(&json_path, json!({ "4": groups }));
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("replace");
+
+    for (original, duplicate, content) in &expected {
+        assert_eq!(&fs::read_link(duplicate).expect("dup link"), original);
+        assert_eq!(
+            fs::read(duplicate).expect("read through link"),
+            content.as_bytes()
+        );
+    }
+
+    let leftovers = fs::read_dir(&shared)
+        .expect("read shared")
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().contains("czkawka-bak"))
+        .count();
+    assert_eq!(leftovers, 0, "every backup should have been removed");
+}
+
+#[test]
+#[cfg(unix)]
+fn hidden_and_directory_staging_keep_full_names_and_clean_up() {
+    for staging in [Staging::Hidden, Staging::Dir] {
+        let temp = TempDir::new().expect("tempdir");
+        let root = canonicalize(temp.path());
+        let originals = root.join("originals");
+        let photos = root.join("photos");
+        fs::create_dir(&originals).expect("create originals");
+        fs::create_dir(&photos).expect("create photos");
+
+        // Same stem, different extensions: both must stage without clashing.
+        let mut groups = Vec::new();
+        let mut pairs = Vec::new();
+        for (index, name) in ["photo.jpg", "photo.png", "archive.tar.gz"]
+            .iter()
+            .enumerate()
+        {
+            let original = originals.join(name);
+            let duplicate = photos.join(name);
+            let content = format!("{:04}", index);
+            fs::write(&original, &content).expect("write original");
+            fs::write(&duplicate, &content).expect("write duplicate");
+            let hash = format!("hash{:04}", index);
+            groups.push(json!([
+                report_entry(&original, &hash),
+                report_entry(&duplicate, &hash)
+            ]));
+            pairs.push((original, duplicate));
+        }
+
+        let json_path = root.join("input.json");
+        write_json(&json_path, json!({ "4": groups }));
+
+        let args = Args {
+            input_file_path: json_path.to_string_lossy().into_owned(),
+            original_to_keep: OriginalToKeep::First,
+            allow_roots: vec![root.clone()],
+            staging,
+            ..Default::default()
+        };
+
+        let files = validate_files(&args.input_file_path).expect("validate");
+        replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+            .expect("replace");
+
+        for (original, duplicate) in &pairs {
+            assert_eq!(&fs::read_link(duplicate).expect("dup link"), original);
+        }
+
+        let remaining = fs::read_dir(&photos)
+            .expect("read photos")
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with('.'))
+            .collect::<Vec<String>>();
+        assert!(remaining.is_empty(), "left behind: {:?}", remaining);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn durable_run_relocates_links_and_removes_backups() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let library = root.join("library");
+    let incoming = root.join("incoming");
+    let copies = root.join("copies");
+    fs::create_dir(&incoming).expect("create incoming");
+    fs::create_dir(&copies).expect("create copies");
+
+    let original = incoming.join("song.flac");
+    let duplicate = copies.join("song.flac");
+    fs::write(&original, b"tune").expect("write original");
+    fs::write(&duplicate, b"tune").expect("write duplicate");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        canonical_dir: Some(library.clone()),
+        staging: Staging::Dir,
+        state_dir: Some(root.join("state")),
+        durable: true,
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new())
+        .expect("durable run");
+
+    let relocated = library.join("incoming").join("song.flac");
+    assert_eq!(fs::read(&relocated).expect("relocated"), b"tune");
+    for path in [&original, &duplicate] {
+        assert!(fs::symlink_metadata(path).expect("link").is_symlink());
+        assert_eq!(fs::read(path).expect("read through link"), b"tune");
+    }
+    assert!(!incoming.join(".czkawka-staging").exists());
+    assert!(!copies.join(".czkawka-staging").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn atomic_groups_restore_every_member_when_one_fails() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+
+    let original = root.join("original.bin");
+    let first = root.join("first.bin");
+    let second = root.join("second.bin");
+    // Fits as a file name, but its hidden backup name does not, so linking the
+    // last member fails after the others were already swapped.
+    let too_long = root.join(format!("{}.bin", "x".repeat(240)));
+    for path in [&original, &first, &second, &too_long] {
+        fs::write(path, b"same").expect("write member");
+    }
+
+    let json_path = root.join("input.json");
+    write_group_report(
+        &json_path,
+        &[&original, &first, &second, &too_long],
+        "hash123",
+    );
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        staging: Staging::Hidden,
+        atomic_groups: true,
+        ..Default::default()
+    };
+
+    let files = validate_files(&args.input_file_path).expect("validate");
+    let result = replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new());
+    assert!(result.is_err(), "the failing member should be reported");
+
+    for path in [&first, &second, &too_long] {
+        let metadata = fs::symlink_metadata(path).expect("member");
+        assert!(metadata.is_file(), "'{}' was not restored", path.display());
+        assert_eq!(fs::read(path).expect("read member"), b"same");
+    }
+
+    let hidden = fs::read_dir(&root)
+        .expect("read root")
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+        .count();
+    assert_eq!(hidden, 0, "no backups should be left behind");
+}
+
+#[test]
+#[cfg(unix)]
+fn cancelled_run_starts_no_new_groups_and_leaves_a_closed_journal() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let original = root.join("keep.bin");
+    let duplicate = root.join("dup.bin");
+    fs::write(&original, b"same").expect("write original");
+    fs::write(&duplicate, b"same").expect("write duplicate");
+
+    let json_path = root.join("input.json");
+    write_pair_report(&json_path, &original, &duplicate);
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        state_dir: Some(root.join("state")),
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+
+    let cancel = CancellationToken::new();
+    cancel.clone().cancel();
+    let err = replace_duplicates_with_symlinks(&args, &files, &cancel)
+        .expect_err("cancelled run must report it");
+    assert!(err.to_string().contains("cancelled"), "{err}");
+    assert!(fs::symlink_metadata(&duplicate).expect("dup").is_file());
+
+    // Nothing was left in flight, so the next run is not blocked.
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("next run");
+    assert_eq!(fs::read_link(&duplicate).expect("dup link"), original);
+}
+
+#[test]
+#[cfg(unix)]
+fn members_that_are_the_same_file_are_never_replaced() {
+    let temp = TempDir::new().expect("tempdir");
+    let root = canonicalize(temp.path());
+    let data = root.join("data");
+    fs::create_dir(&data).expect("data dir");
+
+    let original = data.join("photo.jpg");
+    let hard_link = root.join("photo-link.jpg");
+    let alias_dir = root.join("alias");
+    let copy = root.join("copy.jpg");
+    fs::write(&original, b"only").expect("write original");
+    fs::hard_link(&original, &hard_link).expect("hard link");
+    std::os::unix::fs::symlink(&data, &alias_dir).expect("symlinked dir");
+    fs::write(&copy, b"only").expect("write copy");
+
+    let entry = |path: &std::path::Path| report_entry(path, "hash123");
+    let json_path = root.join("input.json");
+    write_json(
+        &json_path,
+        json!({ "4": [
+            [entry(&original), entry(&alias_dir.join("photo.jpg")), entry(&hard_link), entry(&copy)],
+            [entry(&hard_link), entry(&original)]
+        ] }),
+    );
+
+    let args = Args {
+        input_file_path: json_path.to_string_lossy().into_owned(),
+        original_to_keep: OriginalToKeep::First,
+        allow_roots: vec![root.clone()],
+        ..Default::default()
+    };
+    let files = validate_files(&args.input_file_path).expect("validate");
+    replace_duplicates_with_symlinks(&args, &files, &CancellationToken::new()).expect("run");
+
+    // Both names of the one physical file stay real; only the separate copy
+    // becomes a link.
+    for path in [&original, &hard_link] {
+        assert!(fs::symlink_metadata(path).expect("member").is_file());
+        assert_eq!(fs::read(path).expect("read member"), b"only");
+    }
+    assert_eq!(fs::read_link(&copy).expect("copy link"), original);
+}